@@ -0,0 +1,134 @@
+use crate::config::{load_toml, save_toml};
+use ggez::Context;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+const RECORD_PATH: &str = "/achievements.toml";
+
+pub const LEVELS_FOR_COLLECTOR: usize = 10;
+pub const SPEEDY_TIME: Duration = Duration::from_secs(60);
+pub const WRONG_SPOTS_FOR_STUBBORN: u32 = 100;
+
+// Longest time what's counted towards achievements goes unsaved
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+// How long the notice for an achievement stays on screen
+pub const TOAST_DURATION: Duration = Duration::from_secs(4);
+
+// Written to the event log by their id
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Achievement {
+    Optimal,
+    Collector,
+    NoUndo,
+    Speedy,
+    Stubborn,
+}
+
+impl Achievement {
+    // What it's saved as, so these shouldn't change
+    pub fn id(self) -> &'static str {
+        match self {
+            Achievement::Optimal => "optimal",
+            Achievement::Collector => "collector",
+            Achievement::NoUndo => "no-undo",
+            Achievement::Speedy => "speedy",
+            Achievement::Stubborn => "stubborn",
+        }
+    }
+
+    pub fn title(self) -> &'static str {
+        match self {
+            Achievement::Optimal => "Not a push wasted",
+            Achievement::Collector => "Collector",
+            Achievement::NoUndo => "No regrets",
+            Achievement::Speedy => "Speedy",
+            Achievement::Stubborn => "Stubborn",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Achievement::Optimal => "Solve a level in the fewest pushes possible",
+            Achievement::Collector => "Solve 10 different levels",
+            Achievement::NoUndo => "Solve a level without undoing a move",
+            Achievement::Speedy => "Solve a level within a minute",
+            Achievement::Stubborn => "Put a box on the wrong spot 100 times",
+        }
+    }
+}
+
+// Unlocked achievements and what's counted towards them, saved to
+// achievements.toml in the user config directory
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AchievementRecord {
+    pub unlocked: BTreeSet<String>,
+    // Fingerprints of the levels that got solved
+    pub solved_levels: BTreeSet<String>,
+    pub wrong_spots: u32,
+}
+
+pub struct Achievements {
+    pub record: AchievementRecord,
+    // Fingerprint of the level being played, and how it's going
+    pub level: String,
+    pub started: Duration,
+    pub used_undo: bool,
+    // Fewest pushes the level takes, if that was worked out ahead of time.
+    // Solving it while playing would hold up the game, so whatever loads
+    // the level sets this.
+    pub optimal_pushes: Option<usize>,
+    // Counted towards since the record was last saved, and whether that
+    // was worth saving right away, like an unlock
+    pub changed: bool,
+    pub save_now: bool,
+    pub saved_at: Duration,
+    // Achievements unlocked this session, with when, to show a notice for
+    pub toasts: Vec<(Achievement, Duration)>,
+}
+
+impl Achievements {
+    pub fn new(record: AchievementRecord) -> Self {
+        Achievements {
+            record,
+            level: String::new(),
+            started: Duration::ZERO,
+            used_undo: false,
+            optimal_pushes: None,
+            changed: false,
+            save_now: false,
+            saved_at: Duration::ZERO,
+            toasts: Vec::new(),
+        }
+    }
+
+    pub fn start_level(&mut self, fingerprint: &str, now: Duration) {
+        self.level = fingerprint.to_string();
+        self.started = now;
+        self.used_undo = false;
+    }
+
+    // Returns whether it wasn't unlocked yet
+    pub fn unlock(&mut self, achievement: Achievement, now: Duration) -> bool {
+        if !self.record.unlocked.insert(achievement.id().to_string()) {
+            return false;
+        }
+
+        self.toasts
+            .retain(|(_, at)| now.saturating_sub(*at) < TOAST_DURATION);
+        self.toasts.push((achievement, now));
+        true
+    }
+}
+
+pub fn load_record(context: &Context) -> AchievementRecord {
+    load_toml(context, RECORD_PATH)
+}
+
+pub fn save_record(context: &Context, record: &AchievementRecord) {
+    save_toml(context, RECORD_PATH, record)
+}
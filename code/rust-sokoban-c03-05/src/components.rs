@@ -1,12 +1,18 @@
 use ggez::audio;
 use ggez::audio::SoundSource;
+use ggez::graphics::Color;
+use ggez::input::gamepad::gilrs::Button;
 use ggez::Context;
-use std::collections::HashMap;
+use hecs::Entity;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
 use std::fmt::Display;
 use std::time::Duration;
 
+use crate::config::{AudioConfig, Channel};
+use crate::constants::{MAP_HEIGHT, MAP_WIDTH};
 use crate::events::Event;
+use crate::logging::AUDIO;
 
 #[derive(Clone, Copy, Eq, Hash, PartialEq)]
 pub struct Position {
@@ -15,8 +21,56 @@ pub struct Position {
     pub z: u8,
 }
 
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Direction {
+    pub const ALL: [Direction; 4] = [
+        Direction::Up,
+        Direction::Down,
+        Direction::Left,
+        Direction::Right,
+    ];
+
+    pub fn opposite(&self) -> Self {
+        match self {
+            Direction::Up => Direction::Down,
+            Direction::Down => Direction::Up,
+            Direction::Left => Direction::Right,
+            Direction::Right => Direction::Left,
+        }
+    }
+
+    // Returns the neighbouring tile in this direction, or None if
+    // that would take us off the map.
+    pub fn step(&self, (x, y): (u8, u8)) -> Option<(u8, u8)> {
+        match self {
+            Direction::Up if y > 0 => Some((x, y - 1)),
+            Direction::Down if y < MAP_HEIGHT - 1 => Some((x, y + 1)),
+            Direction::Left if x > 0 => Some((x - 1, y)),
+            Direction::Right if x < MAP_WIDTH - 1 => Some((x + 1, y)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    Undo,
+    Restart,
+}
+
 pub struct Renderable {
     paths: Vec<String>,
+    tint: Color,
+    // Images to tint and draw instead when the ones in `paths` don't exist
+    fallback_paths: Vec<String>,
+    fallback_tint: Color,
 }
 
 pub enum RenderableKind {
@@ -28,13 +82,48 @@ impl Renderable {
     pub fn new_static(path: &str) -> Self {
         Self {
             paths: vec![path.to_string()],
+            tint: Color::WHITE,
+            fallback_paths: Vec::new(),
+            fallback_tint: Color::WHITE,
         }
     }
 
     pub fn new_animated(paths: Vec<&str>) -> Self {
         Self {
             paths: paths.iter().map(|p| p.to_string()).collect(),
+            tint: Color::WHITE,
+            fallback_paths: Vec::new(),
+            fallback_tint: Color::WHITE,
+        }
+    }
+
+    pub fn with_tint(self, tint: Color) -> Self {
+        Self { tint, ..self }
+    }
+
+    pub fn with_fallback(self, paths: Vec<&str>, tint: Color) -> Self {
+        Self {
+            fallback_paths: paths.iter().map(|p| p.to_string()).collect(),
+            fallback_tint: tint,
+            ..self
+        }
+    }
+
+    pub fn tint(&self) -> Color {
+        self.tint
+    }
+
+    // Switches over to the fallback images (and their tint) if any of the
+    // images of this renderable don't exist, so that all frames match
+    pub fn resolve_fallback(&mut self, exists: impl FnMut(&str) -> bool) {
+        if self.fallback_paths.is_empty() || self.paths.iter().map(String::as_str).all(exists) {
+            return;
         }
+
+        self.paths = (0..self.paths.len())
+            .map(|i| self.fallback_paths[i % self.fallback_paths.len()].clone())
+            .collect();
+        self.tint = self.fallback_tint;
     }
 
     pub fn kind(&self) -> RenderableKind {
@@ -55,20 +144,37 @@ impl Renderable {
 
 pub struct Wall {}
 
-pub struct Player {}
+pub struct Player {
+    // Which player this is, starting at 1, decides the controls it uses
+    pub number: u8,
+}
+
+#[derive(Clone, Debug)]
+pub struct BoxColour {
+    pub name: String,
+    // Used to tint the base sprites when there are no dedicated
+    // images for this colour
+    pub tint: Color,
+}
+
+impl BoxColour {
+    pub fn new(name: &str, tint: Color) -> Self {
+        Self {
+            name: name.to_string(),
+            tint,
+        }
+    }
+}
 
-#[derive(PartialEq)]
-pub enum BoxColour {
-    Red,
-    Blue,
+impl PartialEq for BoxColour {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
 }
 
 impl Display for BoxColour {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        fmt.write_str(match self {
-            BoxColour::Red => "red",
-            BoxColour::Blue => "blue",
-        })?;
+        fmt.write_str(&self.name)?;
         Ok(())
     }
 }
@@ -78,9 +184,70 @@ pub struct Box {
 }
 
 pub struct BoxSpot {
-    pub colour: BoxColour,
+    // None for a wildcard spot that takes a box of any colour
+    pub colour: Option<BoxColour>,
+}
+
+impl BoxSpot {
+    pub fn accepts(&self, the_box: &Box) -> bool {
+        match &self.colour {
+            Some(colour) => *colour == the_box.colour,
+            None => true,
+        }
+    }
+}
+
+// Floor that anything moving onto keeps sliding over
+pub struct Ice;
+
+// Anything stepping onto a teleporter comes out at its partner,
+// as long as nothing is in the way there
+pub struct Teleporter {
+    pub pair: Entity,
+}
+
+// Can only be entered moving in its direction, leaving it goes any way
+pub struct OneWay {
+    pub direction: Direction,
+}
+
+// Players can't cross a hole until a box gets pushed into it, which
+// uses up the box and turns the hole into floor
+#[derive(Default)]
+pub struct Hole {
+    pub filled_by: Option<Entity>,
 }
 
+// When an entity last came out of a teleporter, so it can be faded in
+pub struct Teleported {
+    pub at: Duration,
+}
+
+// Picked up by a player walking over it, opens one door with the same label
+pub struct Key {
+    pub label: u8,
+    pub collected: bool,
+}
+
+// In the way until a player holding a key with the same label walks into it
+pub struct Door {
+    pub label: u8,
+    pub open: bool,
+}
+
+// Opens the gates with the same label while anything stands on it
+pub struct PressurePlate {
+    pub label: u8,
+}
+
+pub struct Gate {
+    pub label: u8,
+    pub open: bool,
+}
+
+// Not drawn, e.g. collected keys and open doors
+pub struct Hidden;
+
 pub struct Movable;
 
 pub struct Immovable;
@@ -107,6 +274,17 @@ impl Display for GameplayState {
 pub struct Gameplay {
     pub state: GameplayState,
     pub moves_count: u32,
+    // Moves that pushed or pulled a box
+    pub pushes_count: u32,
+}
+
+// Rules that come with the level being played rather than the config,
+// set up again by every level that gets loaded
+#[derive(Default)]
+pub struct LevelRules {
+    // Reverse Sokoban: boxes can't be pushed at all, moving away from one
+    // always pulls it along. Levels switch this on with `mode: pull`.
+    pub pull_mode: bool,
 }
 
 #[derive(Default)]
@@ -114,22 +292,280 @@ pub struct Time {
     pub delta: Duration,
 }
 
+// Tracks the direction a player is currently holding down (on the keyboard
+// or a gamepad), so that holding it repeats the move after an initial delay.
+#[derive(Default)]
+pub struct KeyRepeat {
+    pub direction: Option<Direction>,
+    pub held_for: Duration,
+    pub next_repeat_at: Duration,
+    // Set when repeating was cut short (e.g. before a push), until the key is released
+    pub stopped: bool,
+}
+
+// Gamepad state, kept up to date by the gamepad events coming from ggez
+#[derive(Default)]
+pub struct GamepadInput {
+    pub dpad: HashSet<Direction>,
+    pub stick: (f32, f32),
+    pub stick_direction: Option<Direction>,
+    // Directions and actions pressed since the input was last processed
+    pub just_pressed: Vec<Direction>,
+    pub actions: Vec<Action>,
+    // Every other button pressed since then, for the level browser and the
+    // stats screen
+    pub buttons: Vec<Button>,
+}
+
+impl GamepadInput {
+    pub fn is_held(&self, direction: Direction) -> bool {
+        self.dpad.contains(&direction) || self.stick_direction == Some(direction)
+    }
+
+    pub fn was_pressed(&self, button: Button) -> bool {
+        self.buttons.contains(&button)
+    }
+
+    // Takes the directions and buttons pressed since the input was last
+    // processed, the actions they'd have done in the game are dropped
+    pub fn take_presses(&mut self) -> (Vec<Direction>, Vec<Button>) {
+        self.actions.clear();
+        (
+            std::mem::take(&mut self.just_pressed),
+            std::mem::take(&mut self.buttons),
+        )
+    }
+}
+
+// Steps left to walk after the player clicked somewhere on the map,
+// taken one at a time so the walk is animated.
+#[derive(Default)]
+pub struct PlayerPath {
+    pub steps: VecDeque<Direction>,
+    pub until_next_step: Duration,
+    // Box clicked on, the next click picks where to push it to
+    pub selected_box: Option<Entity>,
+}
+
+pub struct Snapshot {
+    pub positions: Vec<(Entity, Position)>,
+    pub keys_collected: Vec<(Entity, bool)>,
+    pub doors_open: Vec<(Entity, bool)>,
+    pub holes_filled: Vec<(Entity, Option<Entity>)>,
+    pub moves_count: u32,
+    pub pushes_count: u32,
+}
+
+// Where everything movable was before each move, most recent last,
+// used to undo moves and to restart the level.
+#[derive(Default)]
+pub struct History {
+    pub snapshots: Vec<Snapshot>,
+}
+
 #[derive(Default)]
 pub struct EventQueue {
     pub events: Vec<Event>,
 }
 
+// How long the music stays turned down for a jingle, like the one
+// for winning a level
+const JINGLE_DUCK: Duration = Duration::from_secs(2);
+
+// A music track that is playing, or fading out
+pub struct MusicTrack {
+    pub name: String,
+    // When it started fading in or out
+    pub since: Duration,
+}
+
+// A sound effect, with its variations
+pub struct Sound {
+    pub variants: Vec<audio::Source>,
+    // How far the pitch may wander either way, 0.1 is up to 10%
+    pub pitch_jitter: f32,
+    // Relative to the sound effects volume
+    pub volume: f32,
+    // How long after playing it won't play again, so holding down
+    // a key doesn't stack up lots of the same sound
+    pub cooldown: Duration,
+    pub last_played: Option<Duration>,
+    // Whether the music gets turned down while it plays
+    pub duck: bool,
+}
+
 #[derive(Default)]
 pub struct AudioStore {
-    pub sounds: HashMap<String, std::boxed::Box<audio::Source>>,
+    // Sound effects by the event they are played for
+    pub sounds: HashMap<String, Sound>,
+    // Background music tracks by name, loaded when they are first played
+    // and turned down while sound effects play
+    pub music: HashMap<String, audio::Source>,
+    // The track the current level asked for, if any
+    pub level_music: Option<String>,
+    pub current_track: Option<MusicTrack>,
+    // Tracks on their way out after switching to another one
+    pub fading_tracks: Vec<MusicTrack>,
+    // Until when the music stays turned down
+    pub ducked_until: Duration,
 }
 
 impl AudioStore {
-    pub fn play_sound(&mut self, context: &mut Context, sound: &str) {
-        if let Some(source) = self.sounds.get_mut(sound) {
-            if source.play_detached(context).is_ok() {
-                println!("Playing sound: {}", sound);
+    pub fn play_sound(&mut self, context: &mut Context, settings: &AudioConfig, sound: &str) {
+        let volume = settings.volume(Channel::Sfx);
+        if volume == 0.0 {
+            return;
+        }
+
+        log::debug!(target: AUDIO, "Playing sound {}", sound);
+        let sound = match self.sounds.get_mut(sound) {
+            Some(sound) => sound,
+            None => return,
+        };
+
+        let now = context.time.time_since_start();
+        if sound
+            .last_played
+            .is_some_and(|last_played| now < last_played + sound.cooldown)
+        {
+            return;
+        }
+        sound.last_played = Some(now);
+
+        let variant = fastrand::usize(..sound.variants.len());
+        let pitch = 1.0 + (fastrand::f32() * 2.0 - 1.0) * sound.pitch_jitter;
+        let source = &mut sound.variants[variant];
+        source.set_volume(volume * sound.volume);
+        source.set_pitch(pitch);
+        if source.play_detached(context).is_ok() && sound.duck {
+            let until = now + Duration::from_millis(settings.duck_ms);
+            self.ducked_until = self.ducked_until.max(until);
+        }
+    }
+
+    // Plays a sound with the music turned down for longer, so it
+    // doesn't get in the way
+    pub fn play_jingle(&mut self, context: &mut Context, settings: &AudioConfig, sound: &str) {
+        self.play_sound(context, settings, sound);
+        self.ducked_until = context.time.time_since_start() + JINGLE_DUCK;
+    }
+
+    // Switches over to the track the level wants, fading out the old one
+    // while the new one fades in. Music is paused while `paused` is set.
+    pub fn update_music(&mut self, context: &mut Context, settings: &AudioConfig, paused: bool) {
+        let now = context.time.time_since_start();
+        let wanted = self
+            .level_music
+            .clone()
+            .unwrap_or_else(|| settings.default_music.clone());
+
+        let current = self.current_track.as_ref().map(|track| track.name.as_str());
+        if current != Some(wanted.as_str()) {
+            if let Some(mut old) = self.current_track.take() {
+                old.since = now;
+                self.fading_tracks.push(old);
+            }
+            self.fading_tracks.retain(|track| track.name != wanted);
+
+            if !wanted.is_empty() && self.load_track(context, &wanted) {
+                let source = self.music.get_mut(&wanted).unwrap();
+                source.set_volume(0.0);
+                if let Err(error) = source.play(context) {
+                    log::warn!(target: AUDIO, "Couldn't play music {}: {}", wanted, error);
+                }
+            }
+            self.current_track = Some(MusicTrack {
+                name: wanted,
+                since: now,
+            });
+        }
+
+        let tracks = self.current_track.iter().chain(self.fading_tracks.iter());
+        for track in tracks {
+            if let Some(source) = self.music.get(&track.name) {
+                if paused && !source.paused() {
+                    source.pause();
+                } else if !paused && source.paused() {
+                    source.resume();
+                }
+            }
+        }
+    }
+
+    // Loads a track from /music, as .ogg or otherwise .wav, unless it's
+    // already loaded. Returns whether the track is there to be played.
+    fn load_track(&mut self, context: &mut Context, name: &str) -> bool {
+        if self.music.contains_key(name) {
+            return true;
+        }
+
+        let source = ["ogg", "wav"]
+            .iter()
+            .map(|extension| format!("/music/{}.{}", name, extension))
+            .find(|path| context.fs.exists(path))
+            .and_then(|path| audio::Source::new(context, path).ok());
+
+        match source {
+            Some(mut source) => {
+                source.set_repeat(true);
+                self.music.insert(name.to_string(), source);
+                true
             }
+            None => {
+                log::warn!(target: AUDIO, "Couldn't load music {}", name);
+                false
+            }
+        }
+    }
+
+    // Brings the volume of every source in line with the settings, sound
+    // effects that are already playing keep the volume they started with
+    pub fn apply_volumes(&mut self, context: &mut Context, settings: &AudioConfig) {
+        let now = context.time.time_since_start();
+
+        let sfx_volume = settings.volume(Channel::Sfx);
+        for sound in self.sounds.values_mut() {
+            for source in sound.variants.iter_mut() {
+                source.set_volume(sfx_volume * sound.volume);
+            }
+        }
+
+        let mut music_volume = settings.volume(Channel::Music);
+        if now < self.ducked_until {
+            music_volume *= settings.duck_volume;
         }
+
+        // How far along a crossfade that started at `since` is, from 0 to 1
+        let crossfade = Duration::from_millis(settings.crossfade_ms);
+        let progress = |since: Duration| {
+            if crossfade.is_zero() {
+                1.0
+            } else {
+                ((now - since).as_secs_f32() / crossfade.as_secs_f32()).min(1.0)
+            }
+        };
+
+        if let Some(track) = &self.current_track {
+            if let Some(source) = self.music.get_mut(&track.name) {
+                source.set_volume(music_volume * progress(track.since));
+            }
+        }
+
+        let music = &mut self.music;
+        self.fading_tracks.retain(|track| {
+            let source = match music.get_mut(&track.name) {
+                Some(source) => source,
+                None => return false,
+            };
+
+            let left = 1.0 - progress(track.since);
+            if left > 0.0 {
+                source.set_volume(music_volume * left);
+                true
+            } else {
+                let _ = source.stop(context);
+                false
+            }
+        });
     }
 }
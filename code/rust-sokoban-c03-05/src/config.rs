@@ -0,0 +1,198 @@
+use ggez::Context;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use std::io::{Read, Write};
+
+const CONFIG_PATH: &str = "/config.toml";
+
+// User settings, read from config.toml in the user config directory.
+// Every field has a default, so a partial (or missing) file is fine.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    pub input: InputConfig,
+    pub rules: Rules,
+    pub audio: AudioConfig,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct InputConfig {
+    // How long a direction key has to be held before it starts repeating
+    pub repeat_delay_ms: u64,
+
+    // Time between two repeated moves once repeating has started
+    pub repeat_interval_ms: u64,
+
+    // Stop repeating instead of pushing a box, so holding a key
+    // never pushes a box by accident
+    pub stop_repeat_before_push: bool,
+
+    // Time between two steps when walking to a tile that was clicked on
+    pub path_step_interval_ms: u64,
+
+    // How far the left stick has to be pushed before it counts as a direction
+    pub gamepad_deadzone: f32,
+}
+
+impl Default for InputConfig {
+    fn default() -> Self {
+        Self {
+            repeat_delay_ms: 250,
+            repeat_interval_ms: 100,
+            stop_repeat_before_push: true,
+            path_step_interval_ms: 120,
+            gamepad_deadzone: 0.5,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Rules {
+    // How many boxes in a row the player can push at once,
+    // standard Sokoban only allows a single one
+    pub max_push_chain: usize,
+
+    // Whether the player can pull a box by moving away from it
+    // (holding shift), as in "reverse Sokoban"
+    pub allow_pull: bool,
+
+    // Whether boxes go through teleporters too, players always do
+    pub teleport_boxes: bool,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            max_push_chain: 1,
+            allow_pull: false,
+            teleport_boxes: true,
+        }
+    }
+}
+
+// Volumes go from 0.0 (silent) to 1.0 (full volume), the volume a sound
+// plays at is the master volume times that of its channel.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AudioConfig {
+    pub master_volume: f32,
+    pub sfx_volume: f32,
+    pub music_volume: f32,
+    pub muted: bool,
+
+    // What the music volume gets multiplied by while a sound effect plays,
+    // and for how long after it started
+    pub duck_volume: f32,
+    pub duck_ms: u64,
+
+    // Music for levels that don't pick their own with a `music:` line,
+    // empty for none
+    pub default_music: String,
+    // How long switching from one track to another takes
+    pub crossfade_ms: u64,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self {
+            master_volume: 1.0,
+            sfx_volume: 1.0,
+            music_volume: 0.6,
+            muted: false,
+            duck_volume: 0.5,
+            duck_ms: 400,
+            default_music: "calm".to_string(),
+            crossfade_ms: 1500,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Channel {
+    Sfx,
+    Music,
+}
+
+impl AudioConfig {
+    pub fn volume(&self, channel: Channel) -> f32 {
+        if self.muted {
+            return 0.0;
+        }
+
+        let channel_volume = match channel {
+            Channel::Sfx => self.sfx_volume,
+            Channel::Music => self.music_volume,
+        };
+        (self.master_volume * channel_volume).clamp(0.0, 1.0)
+    }
+}
+
+pub fn load_config(context: &Context) -> Config {
+    load_toml(context, CONFIG_PATH)
+}
+
+// Only the audio settings change in game. The rest of the file is kept
+// the way it is on disk, so nothing that was changed while playing ends
+// up in it. A file that is there but doesn't parse is left alone, so it
+// can still be fixed by hand.
+pub fn save_audio_config(context: &Context, audio: &AudioConfig) {
+    let mut config: Config =
+        match read_file(context, CONFIG_PATH).map(|contents| toml::from_str(&contents)) {
+            Some(Ok(config)) => config,
+            Some(Err(error)) => {
+                log::warn!(
+                    "Not saving the audio settings, {} is invalid: {}",
+                    CONFIG_PATH,
+                    error
+                );
+                return;
+            }
+            None => Config::default(),
+        };
+    config.audio = audio.clone();
+    save_toml(context, CONFIG_PATH, &config)
+}
+
+// Reads a TOML file from the user directories, falling back to the
+// defaults if it's missing or invalid. Used for everything the game keeps
+// between runs.
+pub fn load_toml<T: DeserializeOwned + Default>(context: &Context, path: &str) -> T {
+    let contents = match read_file(context, path) {
+        Some(contents) => contents,
+        None => return T::default(),
+    };
+
+    match toml::from_str(&contents) {
+        Ok(value) => value,
+        Err(error) => {
+            log::warn!("Invalid {}, using defaults: {}", path, error);
+            T::default()
+        }
+    }
+}
+
+fn read_file(context: &Context, path: &str) -> Option<String> {
+    let mut contents = String::new();
+    let read = context
+        .fs
+        .open(path)
+        .and_then(|mut file| Ok(file.read_to_string(&mut contents)?));
+    read.ok().map(|_| contents)
+}
+
+// Writes a TOML file to the user config directory, the only one ggez
+// lets us write to
+pub fn save_toml<T: Serialize>(context: &Context, path: &str, value: &T) {
+    let contents = toml::to_string(value).unwrap();
+    let written = context
+        .fs
+        .create(path)
+        .and_then(|mut file| Ok(file.write_all(contents.as_bytes())?));
+
+    if let Err(error) = written {
+        log::warn!("Couldn't save {}: {}", path, error);
+    }
+}
@@ -0,0 +1,246 @@
+use crate::achievements::Achievements;
+use crate::config::{load_toml, save_toml};
+use crate::generator::{generate, rate, GeneratedLevel, GeneratorSettings};
+use crate::map::{load_map, load_sounds, resolve_images};
+use crate::resources::Resources;
+use ggez::Context;
+use hecs::World;
+use serde::{Deserialize, Serialize};
+
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const RECORD_PATH: &str = "/daily.toml";
+const RESULT_PATH: &str = "/daily_result.txt";
+
+// How many seeds past the one for the day to try, should the generator
+// not find a level for it
+const MAX_SEED_OFFSET: u64 = 4;
+
+// Played instead of the day's level if none could be generated
+const FALLBACK_LEVEL: &str = "
+W W W W W W W
+W . . . . . W
+W . RB . BB . W
+W . . P . . W
+W RS . . . BS W
+W W W W W W W
+";
+
+// A day's level, kept in daily.toml so it only gets generated once
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DailyLevel {
+    pub day: u64,
+    pub map: String,
+    pub pushes: usize,
+}
+
+// What the player did in earlier daily challenges, saved to daily.toml
+// in the user config directory.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DailyRecord {
+    // Day (counted from 1970-01-01) of the last challenge that got solved
+    pub last_solved: Option<u64>,
+    // Days in a row the challenge got solved, up to `last_solved`
+    pub streak: u32,
+    pub best_streak: u32,
+    // The level of the last day the game was started in daily mode
+    pub level: Option<DailyLevel>,
+}
+
+// The daily challenge being played, only there in daily mode
+pub struct Daily {
+    pub day: u64,
+    pub record: DailyRecord,
+    // Shareable summary, once today's challenge is solved
+    pub result: Option<String>,
+}
+
+impl Daily {
+    pub fn solved_today(&self) -> bool {
+        self.record.last_solved == Some(self.day)
+    }
+
+    // The streak as it stands today, a day without solving breaks it
+    pub fn current_streak(&self) -> u32 {
+        match self.record.last_solved {
+            Some(day) if day + 1 >= self.day => self.record.streak,
+            _ => 0,
+        }
+    }
+}
+
+// Today in UTC, as days since 1970-01-01, so everyone gets the same
+// challenge on the same day without needing a time zone database
+pub fn today() -> u64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO);
+    since_epoch.as_secs() / (24 * 60 * 60)
+}
+
+// Formats a day since 1970-01-01 as YYYY-MM-DD, using the conversion from
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn format_day(day: u64) -> String {
+    let z = day as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let d = day_of_year - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = year_of_era + era * 400 + if m <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+// The level for a day, always the same one for the same day. The game
+// waits for it on startup, so the generator gets less time than usual.
+pub fn daily_level(day: u64) -> Option<GeneratedLevel> {
+    (0..MAX_SEED_OFFSET).find_map(|offset| {
+        let settings = GeneratorSettings {
+            seed: day * MAX_SEED_OFFSET + offset,
+            boxes: 3,
+            difficulty: 25,
+            attempts: 10,
+            max_solver_states: 30_000,
+            ..Default::default()
+        };
+        generate(&settings).unwrap_or_else(|error| {
+            log::error!("Bad settings for the daily level: {}", error);
+            None
+        })
+    })
+}
+
+pub fn initialize_daily(world: &mut World, resources: &mut Resources, context: &mut Context) {
+    let day = today();
+    let mut record = load_record(context);
+
+    // Today's level from daily.toml if it's there and still loads, or else
+    // a new one, which gets kept for the next time
+    let cached = record.level.clone().filter(|level| {
+        level.day == day
+            && load_map(&mut World::new(), &Resources::default(), level.map.clone()).is_ok()
+    });
+    let level = cached.or_else(|| {
+        let generated = daily_level(day)?;
+        let level = DailyLevel {
+            day,
+            map: generated.map,
+            pushes: generated.difficulty.pushes,
+        };
+        record.level = Some(level.clone());
+        save_record(context, &record);
+        Some(level)
+    });
+
+    let (map, pushes) = match level {
+        Some(level) => (level.map, Some(level.pushes)),
+        None => {
+            log::error!(
+                "No daily level could be generated for {}, playing a fallback level",
+                format_day(day)
+            );
+            (
+                FALLBACK_LEVEL.to_string(),
+                rate(FALLBACK_LEVEL).map(|difficulty| difficulty.pushes),
+            )
+        }
+    };
+
+    let map = format!("title: Daily {}\n{}", format_day(day), map);
+    load_map(world, resources, map).expect("daily levels are valid");
+    resolve_images(world, context);
+    // The generator solved the level already
+    if let Ok(mut achievements) = resources.get_mut::<Achievements>() {
+        achievements.optimal_pushes = pushes;
+    }
+    load_sounds(resources, context);
+
+    resources.insert(Daily {
+        day,
+        record,
+        result: None,
+    });
+}
+
+pub fn load_record(context: &Context) -> DailyRecord {
+    load_toml(context, RECORD_PATH)
+}
+
+pub fn save_record(context: &Context, record: &DailyRecord) {
+    save_toml(context, RECORD_PATH, record)
+}
+
+// Writes the result to share to daily_result.txt, next to the record
+pub fn save_result(context: &Context, result: &str) {
+    let written = context
+        .fs
+        .create(RESULT_PATH)
+        .and_then(|mut file| Ok(writeln!(file, "{}", result)?));
+
+    match written {
+        Ok(()) => log::info!(
+            "Saved the daily result to {}",
+            context
+                .fs
+                .user_config_dir()
+                .join("daily_result.txt")
+                .display()
+        ),
+        Err(error) => log::warn!("Couldn't save the daily result: {}", error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_fallback_level_loads_and_can_be_solved() {
+        assert!(load_map(
+            &mut World::new(),
+            &Resources::default(),
+            FALLBACK_LEVEL.to_string()
+        )
+        .is_ok());
+        assert!(rate(FALLBACK_LEVEL).is_some());
+    }
+
+    #[test]
+    fn the_level_is_kept_with_the_record() {
+        let record = DailyRecord {
+            last_solved: Some(20000),
+            streak: 2,
+            best_streak: 5,
+            level: Some(DailyLevel {
+                day: 20001,
+                map: "W P RB RS W".to_string(),
+                pushes: 1,
+            }),
+        };
+
+        let saved = toml::to_string(&record).unwrap();
+        let loaded: DailyRecord = toml::from_str(&saved).unwrap();
+        let level = loaded.level.unwrap();
+        assert_eq!(
+            (level.day, level.map.as_str(), level.pushes),
+            (20001, "W P RB RS W", 1)
+        );
+        assert_eq!(loaded.streak, 2);
+
+        // Records from before levels were kept still load
+        let old: DailyRecord = toml::from_str("streak = 3\nbest_streak = 3").unwrap();
+        assert!(old.level.is_none());
+    }
+
+    #[test]
+    fn formats_days_as_dates() {
+        assert_eq!(format_day(0), "1970-01-01");
+        assert_eq!(format_day(20000), "2024-10-04");
+    }
+}
@@ -1,4 +1,5 @@
 use crate::components::*;
+use ggez::graphics::Color;
 use hecs::{Entity, World};
 
 pub fn create_wall(world: &mut World, position: Position) -> Entity {
@@ -17,51 +18,131 @@ pub fn create_floor(world: &mut World, position: Position) -> Entity {
     ))
 }
 
+pub fn create_ice(world: &mut World, position: Position) -> Entity {
+    world.spawn((
+        Position { z: 5, ..position },
+        Renderable::new_static("/images/floor.png").with_tint(Color::new(0.7, 0.9, 1.0, 1.0)),
+        Ice {},
+    ))
+}
+
+pub fn create_one_way(world: &mut World, position: Position, direction: Direction) -> Entity {
+    let path = match direction {
+        Direction::Up => "/images/one_way_up.png",
+        Direction::Down => "/images/one_way_down.png",
+        Direction::Left => "/images/one_way_left.png",
+        Direction::Right => "/images/one_way_right.png",
+    };
+    world.spawn((
+        Position { z: 5, ..position },
+        Renderable::new_static(path),
+        OneWay { direction },
+    ))
+}
+
+pub fn create_hole(world: &mut World, position: Position) -> Entity {
+    world.spawn((
+        Position { z: 6, ..position },
+        Renderable::new_static("/images/hole.png"),
+        Hole::default(),
+    ))
+}
+
+// The Teleporter component is added once its partner exists
+pub fn create_teleporter(world: &mut World, position: Position) -> Entity {
+    world.spawn((
+        Position { z: 5, ..position },
+        Renderable::new_static("/images/floor.png").with_tint(Color::new(0.8, 0.6, 1.0, 1.0)),
+    ))
+}
+
+pub fn create_key(world: &mut World, position: Position, label: u8) -> Entity {
+    world.spawn((
+        Position { z: 9, ..position },
+        Renderable::new_static("/images/box_spot_base.png")
+            .with_tint(Color::from_rgb(230, 190, 40)),
+        Key {
+            label,
+            collected: false,
+        },
+    ))
+}
+
+pub fn create_door(world: &mut World, position: Position, label: u8) -> Entity {
+    world.spawn((
+        Position { z: 10, ..position },
+        Renderable::new_static("/images/wall.png").with_tint(Color::from_rgb(170, 110, 60)),
+        Door { label, open: false },
+        Immovable {},
+    ))
+}
+
+pub fn create_pressure_plate(world: &mut World, position: Position, label: u8) -> Entity {
+    world.spawn((
+        Position { z: 6, ..position },
+        Renderable::new_static("/images/box_spot_base.png")
+            .with_tint(Color::from_rgb(120, 120, 120)),
+        PressurePlate { label },
+    ))
+}
+
+pub fn create_gate(world: &mut World, position: Position, label: u8) -> Entity {
+    world.spawn((
+        Position { z: 10, ..position },
+        Renderable::new_static("/images/wall.png").with_tint(Color::from_rgb(140, 160, 200)),
+        Gate { label, open: false },
+        Immovable {},
+    ))
+}
+
 pub fn create_box(world: &mut World, position: Position, colour: BoxColour) -> Entity {
     world.spawn((
         Position { z: 10, ..position },
         Renderable::new_animated(vec![
             &format!("/images/box_{}_1.png", colour),
             &format!("/images/box_{}_2.png", colour),
-        ]),
+        ])
+        .with_fallback(
+            vec!["/images/box_base_1.png", "/images/box_base_2.png"],
+            colour.tint,
+        ),
         Box { colour },
         Movable {},
     ))
 }
 
-pub fn create_box_spot(world: &mut World, position: Position, colour: BoxColour) -> Entity {
+pub fn create_box_spot(world: &mut World, position: Position, colour: Option<BoxColour>) -> Entity {
+    let (name, tint) = match &colour {
+        Some(colour) => (colour.name.as_str(), colour.tint),
+        None => ("any", Color::new(0.5, 0.5, 0.5, 1.0)),
+    };
+
     world.spawn((
         Position { z: 9, ..position },
-        Renderable::new_static(&format!("/images/box_spot_{}.png", colour)),
+        Renderable::new_static(&format!("/images/box_spot_{}.png", name))
+            .with_fallback(vec!["/images/box_spot_base.png"], tint),
         BoxSpot { colour },
     ))
 }
 
-pub fn create_player(world: &mut World, position: Position) -> Entity {
+pub fn create_player(world: &mut World, position: Position, number: u8) -> Entity {
+    let renderable = Renderable::new_animated(vec![
+        "/images/player_1.png",
+        "/images/player_2.png",
+        "/images/player_3.png",
+    ]);
+
+    // Tint everyone but the first player so they can tell each other apart
+    let renderable = match number {
+        1 => renderable,
+        _ => renderable.with_tint(Color::new(0.6, 0.8, 1.0, 1.0)),
+    };
+
     world.spawn((
         Position { z: 10, ..position },
-        Renderable::new_animated(vec![
-            "/images/player_1.png",
-            "/images/player_2.png",
-            "/images/player_3.png",
-        ]),
-        Player {},
+        renderable,
+        Player { number },
+        KeyRepeat::default(),
         Movable {},
     ))
 }
-
-pub fn create_gameplay(world: &mut World) -> Entity {
-    world.spawn((Gameplay::default(),))
-}
-
-pub fn create_time(world: &mut World) -> Entity {
-    world.spawn((Time::default(),))
-}
-
-pub fn create_event_queue(world: &mut World) -> Entity {
-    world.spawn((EventQueue::default(),))
-}
-
-pub fn create_audio_store(world: &mut World) -> Entity {
-    world.spawn((AudioStore::default(),))
-}
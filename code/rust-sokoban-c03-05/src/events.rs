@@ -1,23 +1,369 @@
-use hecs::Entity;
+use crate::achievements::Achievement;
+use crate::logging::EVENTS;
+use crate::resources::{ResourceError, Resources};
+use ggez::Context;
+use hecs::{Entity, World};
+use serde::{Serialize, Serializer};
 
-#[derive(Debug)]
+use std::collections::VecDeque;
+
+// Entities are written to the event log by their id, which stays the same
+// for as long as the entity is around
+fn serialize_entity<S: Serializer>(entity: &Entity, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u32(entity.id())
+}
+
+#[derive(Debug, Serialize)]
 pub struct EntityMoved {
+    #[serde(serialize_with = "serialize_entity")]
+    pub entity: Entity,
+    // Where it moved from
+    pub from: (u8, u8),
+}
+
+#[derive(Debug, Serialize)]
+pub struct EntitySlid {
+    #[serde(serialize_with = "serialize_entity")]
+    pub entity: Entity,
+    // Whether it came to a halt against something rather than
+    // by sliding off the ice
+    pub hit_obstacle: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EntityTeleported {
+    #[serde(serialize_with = "serialize_entity")]
     pub entity: Entity,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
+pub struct LevelStarted {
+    // Stays the same for as long as the level doesn't change
+    pub fingerprint: String,
+    pub title: String,
+}
+
+#[derive(Debug, Serialize)]
 pub struct BoxPlacedOnSpot {
     pub is_correct_spot: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub enum Event {
+    // Fired when a level is loaded
+    LevelStarted(LevelStarted),
+
     // Fired when the player hits an obstacle like a wall
     PlayerHitObstacle,
 
     // Fired when an entity is moved
     EntityMoved(EntityMoved),
 
+    // Fired when an entity that was moved kept sliding over ice,
+    // after the entity moved event for it
+    EntitySlid(EntitySlid),
+
+    // Fired when an entity that was moved came out of a teleporter,
+    // after the entity moved event for it
+    EntityTeleported(EntityTeleported),
+
+    // Fired when a player picks up a key
+    KeyCollected,
+
+    // Fired when a player walks into a door holding its key
+    DoorUnlocked,
+
+    // Fired when gates open or close, as something steps on
+    // or off their pressure plates
+    GateOpened,
+    GateClosed,
+
+    // Fired when a box is pushed into a hole, filling it
+    HoleFilled,
+
+    // Fired when a box is pushed (or pulled), after the entity moved
+    // event for it
+    BoxPushed,
+
     // Fired when the box is placed on a spot
     BoxPlacedOnSpot(BoxPlacedOnSpot),
+
+    // Fired when a box is moved off the spot it was on
+    BoxRemovedFromSpot,
+
+    // Fired when the last box lands on its spot
+    LevelWon,
+
+    // Fired when a move is taken back
+    MoveUndone,
+
+    // Fired when the level is started over, after at least one move
+    LevelRestarted,
+
+    // Fired when the level browser opens, closes or its selection changes
+    MenuNavigated,
+
+    // Fired when an achievement is unlocked for the first time
+    AchievementUnlocked(Achievement),
+}
+
+// What kind of event it is, leaving out the details, to subscribe to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum EventKind {
+    LevelStarted,
+    PlayerHitObstacle,
+    EntityMoved,
+    EntitySlid,
+    EntityTeleported,
+    KeyCollected,
+    DoorUnlocked,
+    GateOpened,
+    GateClosed,
+    HoleFilled,
+    BoxPushed,
+    BoxPlacedOnSpot,
+    BoxRemovedFromSpot,
+    LevelWon,
+    MoveUndone,
+    LevelRestarted,
+    MenuNavigated,
+    AchievementUnlocked,
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::LevelStarted(_) => EventKind::LevelStarted,
+            Event::PlayerHitObstacle => EventKind::PlayerHitObstacle,
+            Event::EntityMoved(_) => EventKind::EntityMoved,
+            Event::EntitySlid(_) => EventKind::EntitySlid,
+            Event::EntityTeleported(_) => EventKind::EntityTeleported,
+            Event::KeyCollected => EventKind::KeyCollected,
+            Event::DoorUnlocked => EventKind::DoorUnlocked,
+            Event::GateOpened => EventKind::GateOpened,
+            Event::GateClosed => EventKind::GateClosed,
+            Event::HoleFilled => EventKind::HoleFilled,
+            Event::BoxPushed => EventKind::BoxPushed,
+            Event::BoxPlacedOnSpot(_) => EventKind::BoxPlacedOnSpot,
+            Event::BoxRemovedFromSpot => EventKind::BoxRemovedFromSpot,
+            Event::LevelWon => EventKind::LevelWon,
+            Event::MoveUndone => EventKind::MoveUndone,
+            Event::LevelRestarted => EventKind::LevelRestarted,
+            Event::MenuNavigated => EventKind::MenuNavigated,
+            Event::AchievementUnlocked(_) => EventKind::AchievementUnlocked,
+        }
+    }
+}
+
+// Reacts to an event. Any events it fires are handled in the same frame,
+// after the ones that were already waiting. The context is the ggez one in
+// the game, handlers that don't need it (most of them) take any context,
+// so they can also be tried out without a window.
+pub type Handler<C> =
+    fn(&mut World, &Resources, &mut C, &Event, &mut Vec<Event>) -> Result<(), ResourceError>;
+
+struct Subscription<C> {
+    // None for every kind of event
+    kinds: Option<Vec<EventKind>>,
+    handler: Handler<C>,
+}
+
+// Stops events that keep firing each other from hanging the game
+const MAX_EVENTS_PER_FRAME: usize = 1000;
+
+// Hands the events of a frame out to the systems that subscribed to them.
+// Events go out in the order they were fired, and each one goes to its
+// subscribers in the order they subscribed, so the same events always
+// play out the same way.
+pub struct EventBus<C = Context> {
+    subscriptions: Vec<Subscription<C>>,
+}
+
+impl<C> Default for EventBus<C> {
+    fn default() -> Self {
+        EventBus {
+            subscriptions: Vec::new(),
+        }
+    }
+}
+
+impl<C> EventBus<C> {
+    pub fn subscribe(&mut self, kinds: &[EventKind], handler: Handler<C>) {
+        self.subscriptions.push(Subscription {
+            kinds: Some(kinds.to_vec()),
+            handler,
+        });
+    }
+
+    pub fn subscribe_all(&mut self, handler: Handler<C>) {
+        self.subscriptions.push(Subscription {
+            kinds: None,
+            handler,
+        });
+    }
+
+    // A handler that can't get hold of a resource misses the event, the
+    // others still get it
+    pub fn dispatch(
+        &self,
+        world: &mut World,
+        resources: &Resources,
+        context: &mut C,
+        events: Vec<Event>,
+    ) {
+        let mut queue = VecDeque::from(events);
+        let mut handled = 0;
+
+        while let Some(event) = queue.pop_front() {
+            if handled == MAX_EVENTS_PER_FRAME {
+                log::warn!(
+                    target: EVENTS,
+                    "Too many events in one frame, dropped {}",
+                    queue.len() + 1
+                );
+                return;
+            }
+            handled += 1;
+
+            let kind = event.kind();
+            let mut fired = Vec::new();
+            for subscription in self.subscriptions.iter().filter(|subscription| {
+                subscription
+                    .kinds
+                    .as_ref()
+                    .is_none_or(|kinds| kinds.contains(&kind))
+            }) {
+                if let Err(error) =
+                    (subscription.handler)(world, resources, context, &event, &mut fired)
+                {
+                    log::error!(target: EVENTS, "Handling {:?} failed: {}", kind, error);
+                }
+            }
+            queue.extend(fired);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The context of these handlers is a list of what they saw, in order
+    type Seen = Vec<String>;
+
+    fn first(
+        _: &mut World,
+        _: &Resources,
+        seen: &mut Seen,
+        event: &Event,
+        fired: &mut Vec<Event>,
+    ) -> Result<(), ResourceError> {
+        seen.push(format!("first {:?}", event.kind()));
+        if let Event::KeyCollected = event {
+            fired.push(Event::DoorUnlocked);
+        }
+        Ok(())
+    }
+
+    fn second(
+        _: &mut World,
+        _: &Resources,
+        seen: &mut Seen,
+        event: &Event,
+        _: &mut Vec<Event>,
+    ) -> Result<(), ResourceError> {
+        seen.push(format!("second {:?}", event.kind()));
+        Ok(())
+    }
+
+    fn failing(
+        _: &mut World,
+        resources: &Resources,
+        _: &mut Seen,
+        _: &Event,
+        _: &mut Vec<Event>,
+    ) -> Result<(), ResourceError> {
+        resources.get::<u32>()?;
+        Ok(())
+    }
+
+    fn echo(
+        _: &mut World,
+        _: &Resources,
+        seen: &mut Seen,
+        _: &Event,
+        fired: &mut Vec<Event>,
+    ) -> Result<(), ResourceError> {
+        seen.push("echo".to_string());
+        fired.push(Event::MenuNavigated);
+        Ok(())
+    }
+
+    fn dispatch(event_bus: &EventBus<Seen>, events: Vec<Event>) -> Seen {
+        let mut seen = Vec::new();
+        event_bus.dispatch(&mut World::new(), &Resources::default(), &mut seen, events);
+        seen
+    }
+
+    #[test]
+    fn fired_events_are_handled_in_the_same_frame_after_waiting_ones() {
+        let mut event_bus = EventBus::default();
+        event_bus.subscribe_all(first);
+        event_bus.subscribe_all(second);
+
+        let seen = dispatch(&event_bus, vec![Event::KeyCollected, Event::GateOpened]);
+        assert_eq!(
+            seen,
+            [
+                "first KeyCollected",
+                "second KeyCollected",
+                "first GateOpened",
+                "second GateOpened",
+                "first DoorUnlocked",
+                "second DoorUnlocked",
+            ]
+        );
+    }
+
+    #[test]
+    fn subscribers_get_events_in_subscription_order() {
+        let mut event_bus = EventBus::default();
+        event_bus.subscribe_all(second);
+        event_bus.subscribe_all(first);
+
+        let seen = dispatch(&event_bus, vec![Event::LevelWon]);
+        assert_eq!(seen, ["second LevelWon", "first LevelWon"]);
+    }
+
+    #[test]
+    fn subscribers_only_get_their_kinds() {
+        let mut event_bus = EventBus::default();
+        event_bus.subscribe(&[EventKind::LevelWon], first);
+        event_bus.subscribe(&[EventKind::GateOpened, EventKind::GateClosed], second);
+
+        let seen = dispatch(
+            &event_bus,
+            vec![Event::GateClosed, Event::LevelWon, Event::HoleFilled],
+        );
+        assert_eq!(seen, ["second GateClosed", "first LevelWon"]);
+    }
+
+    #[test]
+    fn failing_handlers_dont_stop_the_others() {
+        let mut event_bus = EventBus::default();
+        event_bus.subscribe_all(failing);
+        event_bus.subscribe_all(second);
+
+        let seen = dispatch(&event_bus, vec![Event::LevelWon]);
+        assert_eq!(seen, ["second LevelWon"]);
+    }
+
+    #[test]
+    fn events_firing_each_other_are_cut_off() {
+        let mut event_bus = EventBus::default();
+        event_bus.subscribe_all(echo);
+
+        let seen = dispatch(&event_bus, vec![Event::MenuNavigated]);
+        assert_eq!(seen.len(), MAX_EVENTS_PER_FRAME);
+    }
 }
@@ -0,0 +1,619 @@
+use crate::components::Direction;
+use crate::constants::{MAP_HEIGHT, MAP_WIDTH};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Write;
+
+// Box colours the generator can use, by their map letter. Red and blue
+// are built into `load_map`, the others get declared in the level.
+const PALETTE: [(char, &str, &str); 4] = [
+    ('R', "red", "#c82828"),
+    ('B', "blue", "#2850c8"),
+    ('G', "green", "#33aa44"),
+    ('Y', "yellow", "#ddbb22"),
+];
+
+// How many candidate levels to try before settling for the one that
+// comes closest to the requested difficulty, unless the settings say
+// otherwise
+const ATTEMPTS: u64 = 40;
+
+// How many states the solver looks at before giving up on a level, again
+// unless the settings say otherwise
+const MAX_SOLVER_STATES: usize = 200_000;
+
+const USAGE: &str = "usage: rust-sokoban generate [--width <n>] [--height <n>] [--boxes <n>] \
+[--colours <letters>] [--seed <n>] [--difficulty <n>]";
+
+// What kind of level to generate, `generate` always gives the same level
+// for the same settings.
+pub struct GeneratorSettings {
+    // Size of the level including the outer walls
+    pub width: u8,
+    pub height: u8,
+    pub boxes: usize,
+    // Colour letters the boxes cycle through, e.g. "RRB" for two red
+    // boxes for every blue one
+    pub colours: Vec<char>,
+    pub seed: u64,
+    // Score to aim for, see `Difficulty::score`
+    pub difficulty: u32,
+    // How hard to look, fewer attempts and solver states give a level
+    // sooner but less often one close to the difficulty
+    pub attempts: u64,
+    pub max_solver_states: usize,
+}
+
+impl Default for GeneratorSettings {
+    fn default() -> Self {
+        Self {
+            width: MAP_WIDTH,
+            height: MAP_HEIGHT,
+            boxes: 3,
+            colours: vec!['R', 'B'],
+            seed: 0,
+            difficulty: 20,
+            attempts: ATTEMPTS,
+            max_solver_states: MAX_SOLVER_STATES,
+        }
+    }
+}
+
+impl GeneratorSettings {
+    // Reads settings from command line arguments like `--boxes 4 --seed 7`,
+    // anything that isn't given keeps its default. The error says what's
+    // wrong with the arguments, followed by the usage.
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut settings = Self::default();
+
+        for pair in args.chunks(2) {
+            let (name, value) = match pair {
+                [name, value] => (name.as_str(), value.as_str()),
+                _ => return Err(format!("missing value for {}\n{}", pair[0], USAGE)),
+            };
+
+            match name {
+                "--width" => settings.width = parse_arg(name, value)?,
+                "--height" => settings.height = parse_arg(name, value)?,
+                "--boxes" => settings.boxes = parse_arg(name, value)?,
+                "--colours" => settings.colours = value.chars().collect(),
+                "--seed" => settings.seed = parse_arg(name, value)?,
+                "--difficulty" => settings.difficulty = parse_arg(name, value)?,
+                _ => return Err(format!("unrecognized argument {}\n{}", name, USAGE)),
+            }
+        }
+
+        settings
+            .check()
+            .map_err(|error| format!("{}\n{}", error, USAGE))?;
+        Ok(settings)
+    }
+
+    // Settings `generate` can't make a level for
+    fn check(&self) -> Result<(), String> {
+        if self.width < 3 || self.height < 3 || self.width > MAP_WIDTH || self.height > MAP_HEIGHT {
+            return Err(format!(
+                "level size must be between 3x3 and {}x{}",
+                MAP_WIDTH, MAP_HEIGHT
+            ));
+        }
+        if self.boxes == 0 {
+            return Err("at least one box is needed".to_string());
+        }
+        if self.colours.is_empty() {
+            return Err("at least one box colour is needed".to_string());
+        }
+        for letter in self.colours.iter() {
+            if !PALETTE.iter().any(|(known, _, _)| known == letter) {
+                return Err(format!("unknown box colour {}", letter));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_arg<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value {} for {}\n{}", value, name, USAGE))
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Difficulty {
+    // Fewest pushes needed to solve the level
+    pub pushes: usize,
+    // Average number of pushes available in the positions the solver
+    // went through, how many ways there are to go wrong
+    pub branching: f32,
+}
+
+impl Difficulty {
+    pub fn score(&self) -> u32 {
+        (self.pushes as f32 * self.branching).round() as u32
+    }
+}
+
+pub struct GeneratedLevel {
+    // The level as understood by `load_map`
+    pub map: String,
+    pub difficulty: Difficulty,
+}
+
+// Generates a solvable level: builds a room, puts every box on a spot and
+// then plays backwards, pulling boxes off their spots. The solver then
+// checks the result and scores it. Out of a number of such candidates
+// the one closest to the requested difficulty wins.
+// Fails on settings it can't make a level for, and gives None if none of
+// the candidates could be solved.
+pub fn generate(settings: &GeneratorSettings) -> Result<Option<GeneratedLevel>, String> {
+    settings.check()?;
+
+    Ok((0..settings.attempts)
+        .filter_map(|attempt| {
+            let mut rng = fastrand::Rng::with_seed(
+                settings.seed.wrapping_mul(ATTEMPTS).wrapping_add(attempt),
+            );
+            let level = build_candidate(settings, &mut rng)?;
+            let difficulty = solve(&level, settings.max_solver_states)?;
+            Some((level, difficulty))
+        })
+        .min_by_key(|(_, difficulty)| {
+            (difficulty.score() as i64 - settings.difficulty as i64).abs()
+        })
+        .map(|(level, difficulty)| GeneratedLevel {
+            map: level.to_map(),
+            difficulty,
+        }))
+}
+
+// Scores an existing level the same way generated levels are scored.
+// Only plain levels made of walls, floor, boxes and coloured spots can be
+// scored, anything else (or a level too big to solve) gives None.
+pub fn rate(map: &str) -> Option<Difficulty> {
+    let pull_mode = map
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("mode:"))
+        .any(|mode| mode.trim() == "pull");
+    if pull_mode {
+        return None;
+    }
+
+    let mut level = Level {
+        width: 0,
+        height: 0,
+        walls: HashSet::new(),
+        spots: Vec::new(),
+        boxes: Vec::new(),
+        player: (0, 0),
+    };
+    let mut player = None;
+
+    // Header lines like `title: ...` all have a colon, map rows never do
+    let rows = map
+        .trim()
+        .lines()
+        .map(|row| row.trim())
+        .filter(|row| !row.contains(':'));
+    for (y, row) in rows.enumerate() {
+        for (x, column) in row.split(' ').enumerate() {
+            let tile = (x as u8, y as u8);
+            let mut chars = column.chars();
+            match (chars.next(), chars.next(), chars.next()) {
+                (Some('W'), None, _) | (Some('N'), None, _) => {
+                    level.walls.insert(tile);
+                }
+                (Some('.'), None, _) => (),
+                (Some('P'), None, _) | (Some('P'), Some('1'), None) => player = Some(tile),
+                (Some(colour), Some('B'), None) if colour != '*' => level.boxes.push(PlacedBox {
+                    colour,
+                    position: tile,
+                }),
+                (Some(colour), Some('S'), None) if colour != '*' => level.spots.push(PlacedBox {
+                    colour,
+                    position: tile,
+                }),
+                _ => return None,
+            }
+            level.width = level.width.max(x as u8 + 1);
+        }
+        level.height = y as u8 + 1;
+    }
+
+    level.player = player?;
+    solve(&level, MAX_SOLVER_STATES)
+}
+
+type Tile = (u8, u8);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct PlacedBox {
+    colour: char,
+    position: Tile,
+}
+
+struct Level {
+    width: u8,
+    height: u8,
+    walls: HashSet<Tile>,
+    spots: Vec<PlacedBox>,
+    boxes: Vec<PlacedBox>,
+    player: Tile,
+}
+
+impl Level {
+    fn is_free(&self, tile: Tile, boxes: &[PlacedBox]) -> bool {
+        tile.0 < self.width
+            && tile.1 < self.height
+            && !self.walls.contains(&tile)
+            && !boxes.iter().any(|the_box| the_box.position == tile)
+    }
+
+    // Every tile the player can walk to without moving a box
+    fn reachable(&self, from: Tile, boxes: &[PlacedBox]) -> HashSet<Tile> {
+        let mut reached = HashSet::from([from]);
+        let mut queue = VecDeque::from([from]);
+
+        while let Some(current) = queue.pop_front() {
+            for direction in Direction::ALL {
+                if let Some(next) = direction.step(current) {
+                    if self.is_free(next, boxes) && reached.insert(next) {
+                        queue.push_back(next);
+                    }
+                }
+            }
+        }
+
+        reached
+    }
+
+    fn to_map(&self) -> String {
+        let mut map = String::new();
+
+        let mut letters: Vec<char> = self.boxes.iter().map(|the_box| the_box.colour).collect();
+        letters.sort_unstable();
+        letters.dedup();
+        for (letter, name, hex) in PALETTE.iter() {
+            if letters.contains(letter) && *letter != 'R' && *letter != 'B' {
+                writeln!(map, "colour: {} {} {}", letter, name, hex).unwrap();
+            }
+        }
+
+        for y in 0..self.height {
+            let row: Vec<String> = (0..self.width)
+                .map(|x| {
+                    let tile = (x, y);
+                    if self.walls.contains(&tile) {
+                        "W".to_string()
+                    } else if tile == self.player {
+                        "P".to_string()
+                    } else if let Some(the_box) = self.boxes.iter().find(|b| b.position == tile) {
+                        format!("{}B", the_box.colour)
+                    } else if let Some(spot) = self.spots.iter().find(|s| s.position == tile) {
+                        format!("{}S", spot.colour)
+                    } else {
+                        ".".to_string()
+                    }
+                })
+                .collect();
+            writeln!(map, "{}", row.join(" ")).unwrap();
+        }
+
+        map
+    }
+}
+
+fn build_candidate(settings: &GeneratorSettings, rng: &mut fastrand::Rng) -> Option<Level> {
+    let (width, height) = (settings.width, settings.height);
+
+    // A room with walls all around, and some more walls inside as long as
+    // they don't cut the room in two
+    let mut walls: HashSet<Tile> = HashSet::new();
+    for x in 0..width {
+        walls.insert((x, 0));
+        walls.insert((x, height - 1));
+    }
+    for y in 0..height {
+        walls.insert((0, y));
+        walls.insert((width - 1, y));
+    }
+
+    let mut floor: Vec<Tile> = (1..height - 1)
+        .flat_map(|y| (1..width - 1).map(move |x| (x, y)))
+        .collect();
+    let inner_walls = floor.len() / 6;
+    for _ in 0..inner_walls {
+        let tile = floor[rng.usize(..floor.len())];
+        let remaining: Vec<Tile> = floor.iter().copied().filter(|t| *t != tile).collect();
+        if is_connected(&remaining) {
+            walls.insert(tile);
+            floor = remaining;
+        }
+    }
+
+    // Boxes start out on their spots, plus one tile for the player
+    if floor.len() < settings.boxes + 1 {
+        return None;
+    }
+    rng.shuffle(&mut floor);
+    let spots: Vec<PlacedBox> = floor
+        .iter()
+        .take(settings.boxes)
+        .enumerate()
+        .map(|(i, position)| PlacedBox {
+            colour: settings.colours[i % settings.colours.len()],
+            position: *position,
+        })
+        .collect();
+
+    let mut level = Level {
+        width,
+        height,
+        walls,
+        boxes: spots.clone(),
+        spots,
+        player: floor[settings.boxes],
+    };
+
+    scramble(&mut level, rng, settings.boxes * 20);
+
+    // Maps have no way of putting anything on top of a spot, which also
+    // rules out levels that start out solved
+    let covered = level
+        .boxes
+        .iter()
+        .map(|the_box| the_box.position)
+        .chain(std::iter::once(level.player))
+        .any(|tile| level.spots.iter().any(|spot| spot.position == tile));
+    if covered {
+        None
+    } else {
+        Some(level)
+    }
+}
+
+fn is_connected(tiles: &[Tile]) -> bool {
+    let tiles: HashSet<Tile> = tiles.iter().copied().collect();
+    let start = match tiles.iter().next() {
+        Some(start) => *start,
+        None => return true,
+    };
+
+    let mut reached = HashSet::from([start]);
+    let mut queue = VecDeque::from([start]);
+    while let Some(current) = queue.pop_front() {
+        for direction in Direction::ALL {
+            if let Some(next) = direction.step(current) {
+                if tiles.contains(&next) && reached.insert(next) {
+                    queue.push_back(next);
+                }
+            }
+        }
+    }
+
+    reached.len() == tiles.len()
+}
+
+// Plays the level backwards: the player walks up to a box and pulls it,
+// which is exactly a push undone, so the level stays solvable.
+fn scramble(level: &mut Level, rng: &mut fastrand::Rng, pulls: usize) {
+    for _ in 0..pulls {
+        let reachable = level.reachable(level.player, &level.boxes);
+
+        // Standing on `from` next to a box, stepping away from it in
+        // `direction` pulls the box onto `from`
+        let mut options: Vec<(Tile, Direction, usize)> = Vec::new();
+        for from in reachable.iter().copied() {
+            for direction in Direction::ALL {
+                let to = match direction.step(from) {
+                    Some(to) if level.is_free(to, &level.boxes) => to,
+                    _ => continue,
+                };
+                let behind = direction.opposite().step(from);
+                if let Some(i) = level.boxes.iter().position(|b| Some(b.position) == behind) {
+                    options.push((to, direction, i));
+                }
+            }
+        }
+
+        if options.is_empty() {
+            break;
+        }
+        // Keep the order independent of how the HashSet iterates, so that
+        // the seed alone decides the level
+        options.sort_unstable_by_key(|(to, direction, i)| (*to, *direction as u8, *i));
+
+        let (to, direction, i) = options[rng.usize(..options.len())];
+        let from = direction.opposite().step(to).unwrap();
+        level.boxes[i].position = from;
+        level.player = to;
+    }
+
+    // Put the player somewhere random it could have walked to
+    let mut reachable: Vec<Tile> = level
+        .reachable(level.player, &level.boxes)
+        .into_iter()
+        .collect();
+    reachable.sort_unstable();
+    level.player = reachable[rng.usize(..reachable.len())];
+}
+
+// Searches for the fewest pushes that solve the level, counting how many
+// pushes are available along the way. Returns None if the level can't be
+// solved, or not within a reasonable number of states.
+fn solve(level: &Level, max_states: usize) -> Option<Difficulty> {
+    // A state is where the boxes are, plus the top-left-most tile the
+    // player can reach, since walking around without pushing is free
+    type State = (Vec<PlacedBox>, Tile);
+
+    let normalize = |player: Tile, boxes: &[PlacedBox]| -> State {
+        let mut boxes = boxes.to_vec();
+        boxes.sort_unstable();
+        let corner = *level
+            .reachable(player, &boxes)
+            .iter()
+            .min_by_key(|(x, y)| (*y, *x))
+            .unwrap();
+        (boxes, corner)
+    };
+
+    let start = normalize(level.player, &level.boxes);
+    let mut pushes: HashMap<State, usize> = HashMap::from([(start.clone(), 0)]);
+    let mut queue = VecDeque::from([start]);
+    let mut options_seen = 0;
+    let mut expanded = 0;
+
+    while let Some(state) = queue.pop_front() {
+        let (boxes, player) = &state;
+        let so_far = pushes[&state];
+
+        if boxes.iter().all(|the_box| level.spots.contains(the_box)) {
+            return Some(Difficulty {
+                pushes: so_far,
+                branching: options_seen as f32 / expanded.max(1) as f32,
+            });
+        }
+        if pushes.len() > max_states {
+            return None;
+        }
+
+        expanded += 1;
+        let reachable = level.reachable(*player, boxes);
+        for (i, the_box) in boxes.iter().enumerate() {
+            for direction in Direction::ALL {
+                // The player has to stand behind the box, the box needs
+                // somewhere free to go
+                let behind = direction.opposite().step(the_box.position);
+                let ahead = direction.step(the_box.position);
+                let ahead = match (behind, ahead) {
+                    (Some(behind), Some(ahead))
+                        if reachable.contains(&behind) && level.is_free(ahead, boxes) =>
+                    {
+                        ahead
+                    }
+                    _ => continue,
+                };
+
+                options_seen += 1;
+                let mut next_boxes = boxes.clone();
+                next_boxes[i].position = ahead;
+                let next = normalize(the_box.position, &next_boxes);
+                if !pushes.contains_key(&next) {
+                    pushes.insert(next.clone(), so_far + 1);
+                    queue.push_back(next);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn small_settings(seed: u64) -> GeneratorSettings {
+        GeneratorSettings {
+            width: 7,
+            height: 7,
+            boxes: 2,
+            seed,
+            ..GeneratorSettings::default()
+        }
+    }
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_level() {
+        let first = generate(&small_settings(7)).unwrap().unwrap();
+        let second = generate(&small_settings(7)).unwrap().unwrap();
+        assert_eq!(first.map, second.map);
+        assert_eq!(first.difficulty.pushes, second.difficulty.pushes);
+    }
+
+    #[test]
+    fn bad_settings_are_errors() {
+        let settings = GeneratorSettings {
+            boxes: 0,
+            ..small_settings(1)
+        };
+        assert!(generate(&settings).is_err());
+    }
+
+    #[test]
+    fn generated_levels_rate_as_generated() {
+        let level = generate(&small_settings(3)).unwrap().unwrap();
+        let rated = rate(&level.map).unwrap();
+        assert_eq!(rated.pushes, level.difficulty.pushes);
+        assert!(rated.pushes > 0);
+    }
+
+    #[test]
+    fn finds_the_fewest_pushes() {
+        let straight = "
+        W W W W W W W
+        W P . RB . RS W
+        W W W W W W W
+        ";
+        assert_eq!(rate(straight).unwrap().pushes, 2);
+
+        // The player has to walk around the box to push it down
+        let around = "
+        W W W W W W
+        W . . . . W
+        W . RB . . W
+        W . P . . W
+        W . RS . . W
+        W W W W W W
+        ";
+        assert_eq!(rate(around).unwrap().pushes, 2);
+    }
+
+    #[test]
+    fn unsolvable_and_unsupported_levels_have_no_rating() {
+        let cornered = "
+        W W W W W
+        W RB . . W
+        W . P RS W
+        W W W W W
+        ";
+        assert!(rate(cornered).is_none());
+
+        let with_key = "
+        W W W W W W
+        W P K1 D1 RB RS W
+        W W W W W W
+        ";
+        assert!(rate(with_key).is_none());
+        assert!(rate("mode: pull\nW P RB RS W").is_none());
+    }
+
+    #[test]
+    fn reads_settings_from_arguments() {
+        let settings =
+            GeneratorSettings::from_args(&args("--boxes 4 --seed 7 --colours RG")).unwrap();
+        assert_eq!(settings.boxes, 4);
+        assert_eq!(settings.seed, 7);
+        assert_eq!(settings.colours, vec!['R', 'G']);
+        assert_eq!(settings.width, MAP_WIDTH);
+    }
+
+    #[test]
+    fn bad_arguments_are_errors_with_the_usage() {
+        for bad in [
+            "--boxes",
+            "--boxes four",
+            "--size 5",
+            "--width 2",
+            "--colours RX",
+            "--colours",
+            "--boxes 0",
+        ] {
+            let error = GeneratorSettings::from_args(&args(bad)).err().unwrap();
+            assert!(error.ends_with(USAGE), "{}: {}", bad, error);
+        }
+    }
+}
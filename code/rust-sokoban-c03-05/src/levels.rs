@@ -0,0 +1,333 @@
+use crate::config::{load_toml, save_toml};
+use crate::generator::rate;
+use ggez::Context;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeSet;
+use std::fmt::{self, Display};
+use std::io::Read;
+
+// Level files live in the levels folder of the resources
+const LEVELS_DIR: &str = "/levels";
+// What we know about each level file, so unchanged levels don't have
+// to be rated again, kept in the user config directory
+const INDEX_PATH: &str = "/levels_index.toml";
+const PROGRESS_PATH: &str = "/progress.toml";
+
+// Metadata of a level file. Title, author and difficulty come from header
+// lines like `title: First steps` at the top of the file, a level without
+// a difficulty line gets rated by the solver instead. The solver also works
+// out the fewest pushes the level takes, for levels it can solve.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct LevelInfo {
+    pub path: String,
+    // Changes whenever the contents of the file do
+    pub fingerprint: String,
+    pub title: String,
+    pub author: String,
+    pub width: u8,
+    pub height: u8,
+    pub boxes: usize,
+    pub difficulty: Option<u32>,
+    #[serde(default)]
+    pub pushes: Option<usize>,
+}
+
+impl LevelInfo {
+    fn from_file(path: &str, contents: &str) -> Self {
+        let mut info = LevelInfo {
+            path: path.to_string(),
+            fingerprint: fingerprint(contents),
+            title: path
+                .trim_start_matches(LEVELS_DIR)
+                .trim_start_matches('/')
+                .to_string(),
+            author: "unknown".to_string(),
+            width: 0,
+            height: 0,
+            boxes: 0,
+            difficulty: None,
+            pushes: None,
+        };
+
+        for line in contents.trim().lines().map(|line| line.trim()) {
+            if let Some(title) = line.strip_prefix("title:") {
+                info.title = title.trim().to_string();
+            } else if let Some(author) = line.strip_prefix("author:") {
+                info.author = author.trim().to_string();
+            } else if let Some(difficulty) = line.strip_prefix("difficulty:") {
+                info.difficulty = difficulty.trim().parse().ok();
+            } else if !line.contains(':') {
+                let columns: Vec<&str> = line.split(' ').collect();
+                info.width = info.width.max(columns.len() as u8);
+                info.height += 1;
+                info.boxes += columns
+                    .iter()
+                    .filter(|column| column.len() == 2 && column.ends_with('B'))
+                    .count();
+            }
+        }
+
+        if let Some(difficulty) = rate(contents) {
+            info.difficulty = info.difficulty.or(Some(difficulty.score()));
+            info.pushes = Some(difficulty.pushes);
+        }
+
+        info
+    }
+
+    pub fn size(&self) -> Size {
+        match self.width as u16 * self.height as u16 {
+            0..=36 => Size::Small,
+            37..=64 => Size::Medium,
+            _ => Size::Large,
+        }
+    }
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LevelIndex {
+    pub levels: Vec<LevelInfo>,
+}
+
+// Which levels have been solved, by path
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Progress {
+    pub solved: BTreeSet<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Size {
+    Small,
+    Medium,
+    Large,
+}
+
+impl Display for Size {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(match self {
+            Size::Small => "small",
+            Size::Medium => "medium",
+            Size::Large => "large",
+        })
+    }
+}
+
+// A quick FNV-1a hash, stable between runs unlike the standard hasher
+pub fn fingerprint(contents: &str) -> String {
+    let hash = contents
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+    format!("{:016x}", hash)
+}
+
+// Goes through the levels folder, reusing whatever the index file already
+// knows about levels that haven't changed, and updates the index file.
+pub fn scan_levels(context: &Context) -> LevelIndex {
+    let cached: LevelIndex = load_toml(context, INDEX_PATH);
+
+    let mut paths: Vec<String> = match context.fs.read_dir(LEVELS_DIR) {
+        Ok(paths) => paths
+            .map(|path| path.to_string_lossy().replace('\\', "/"))
+            .filter(|path| path.ends_with(".txt"))
+            .collect(),
+        Err(_) => return LevelIndex::default(),
+    };
+    paths.sort();
+    paths.dedup();
+
+    let levels: Vec<LevelInfo> = paths
+        .iter()
+        .filter_map(|path| {
+            let contents = read_level(context, path)?;
+            let fingerprint = fingerprint(&contents);
+            let info = match cached
+                .levels
+                .iter()
+                .find(|info| info.path == *path && info.fingerprint == fingerprint)
+            {
+                Some(info) => info.clone(),
+                None => LevelInfo::from_file(path, &contents),
+            };
+            Some(info)
+        })
+        .collect();
+
+    let index = LevelIndex { levels };
+    save_toml(context, INDEX_PATH, &index);
+    index
+}
+
+pub fn read_level(context: &Context, path: &str) -> Option<String> {
+    let mut contents = String::new();
+    context
+        .fs
+        .open(path)
+        .and_then(|mut file| Ok(file.read_to_string(&mut contents)?))
+        .ok()?;
+    Some(contents)
+}
+
+pub fn load_progress(context: &Context) -> Progress {
+    load_toml(context, PROGRESS_PATH)
+}
+
+pub fn save_progress(context: &Context, progress: &Progress) {
+    save_toml(context, PROGRESS_PATH, progress)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SortBy {
+    Title,
+    Size,
+    Boxes,
+    Difficulty,
+    Author,
+}
+
+impl SortBy {
+    pub fn next(self) -> Self {
+        match self {
+            SortBy::Title => SortBy::Size,
+            SortBy::Size => SortBy::Boxes,
+            SortBy::Boxes => SortBy::Difficulty,
+            SortBy::Difficulty => SortBy::Author,
+            SortBy::Author => SortBy::Title,
+        }
+    }
+}
+
+impl Display for SortBy {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(match self {
+            SortBy::Title => "title",
+            SortBy::Size => "size",
+            SortBy::Boxes => "boxes",
+            SortBy::Difficulty => "difficulty",
+            SortBy::Author => "author",
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SolvedFilter {
+    All,
+    Unsolved,
+    Solved,
+}
+
+impl SolvedFilter {
+    pub fn next(self) -> Self {
+        match self {
+            SolvedFilter::All => SolvedFilter::Unsolved,
+            SolvedFilter::Unsolved => SolvedFilter::Solved,
+            SolvedFilter::Solved => SolvedFilter::All,
+        }
+    }
+}
+
+impl Display for SolvedFilter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(match self {
+            SolvedFilter::All => "all",
+            SolvedFilter::Unsolved => "unsolved",
+            SolvedFilter::Solved => "solved",
+        })
+    }
+}
+
+// The in-game list of levels, toggled with L
+pub struct LevelBrowser {
+    pub open: bool,
+    pub index: LevelIndex,
+    pub progress: Progress,
+    pub sort_by: SortBy,
+    pub solved_filter: SolvedFilter,
+    pub size_filter: Option<Size>,
+    pub author_filter: Option<String>,
+    // Position of the selected level in the visible list
+    pub selected: usize,
+    // Path of the level being played, if it came from the collection
+    pub playing: Option<String>,
+}
+
+impl LevelBrowser {
+    pub fn new(index: LevelIndex, progress: Progress) -> Self {
+        Self {
+            open: false,
+            index,
+            progress,
+            sort_by: SortBy::Title,
+            solved_filter: SolvedFilter::All,
+            size_filter: None,
+            author_filter: None,
+            selected: 0,
+            playing: None,
+        }
+    }
+
+    pub fn is_solved(&self, level: &LevelInfo) -> bool {
+        self.progress.solved.contains(&level.path)
+    }
+
+    // The levels that pass all filters, in the chosen order
+    pub fn visible(&self) -> Vec<&LevelInfo> {
+        let mut levels: Vec<&LevelInfo> = self
+            .index
+            .levels
+            .iter()
+            .filter(|level| match self.solved_filter {
+                SolvedFilter::All => true,
+                SolvedFilter::Unsolved => !self.is_solved(level),
+                SolvedFilter::Solved => self.is_solved(level),
+            })
+            .filter(|level| self.size_filter.is_none_or(|size| level.size() == size))
+            .filter(|level| {
+                self.author_filter
+                    .as_ref()
+                    .is_none_or(|author| level.author == *author)
+            })
+            .collect();
+
+        match self.sort_by {
+            SortBy::Title => levels.sort_by(|a, b| a.title.cmp(&b.title)),
+            SortBy::Size => levels.sort_by_key(|level| level.width as u16 * level.height as u16),
+            SortBy::Boxes => levels.sort_by_key(|level| level.boxes),
+            // Levels that couldn't be rated go last
+            SortBy::Difficulty => levels.sort_by_key(|level| level.difficulty.unwrap_or(u32::MAX)),
+            SortBy::Author => levels.sort_by(|a, b| a.author.cmp(&b.author)),
+        }
+
+        levels
+    }
+
+    pub fn next_size_filter(&mut self) {
+        self.size_filter = match self.size_filter {
+            None => Some(Size::Small),
+            Some(Size::Small) => Some(Size::Medium),
+            Some(Size::Medium) => Some(Size::Large),
+            Some(Size::Large) => None,
+        };
+    }
+
+    // Goes through the authors in alphabetical order, then back to everyone
+    pub fn next_author_filter(&mut self) {
+        let authors: BTreeSet<&String> = self
+            .index
+            .levels
+            .iter()
+            .map(|level| &level.author)
+            .collect();
+        self.author_filter = match &self.author_filter {
+            None => authors.into_iter().next().cloned(),
+            Some(current) => authors
+                .into_iter()
+                .find(|author| *author > current)
+                .cloned(),
+        };
+    }
+}
@@ -0,0 +1,137 @@
+use crate::events::{Event, EventKind};
+use log::LevelFilter;
+use serde::Serialize;
+
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+
+// Log targets for the parts of the game, so they can be turned up one at
+// a time, like `--log audio=debug` or `RUST_LOG=events=trace`
+pub const EVENTS: &str = "events";
+pub const INPUT: &str = "input";
+pub const AUDIO: &str = "audio";
+pub const RENDERING: &str = "rendering";
+
+// Logging options taken from the command line
+#[derive(Default)]
+pub struct LogOptions {
+    // Same format as RUST_LOG, and wins over it
+    pub filter: Option<String>,
+    // Where to write every event of the session, one JSON object per line
+    pub event_log: Option<String>,
+}
+
+// Takes `--log <filter>` and `--event-log <file>` out of the arguments,
+// wherever they are, leaving the rest for the subcommands
+pub fn take_log_options(args: &mut Vec<String>) -> LogOptions {
+    let mut options = LogOptions::default();
+    let mut rest = Vec::new();
+
+    let mut args_iter = std::mem::take(args).into_iter();
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            "--log" => options.filter = args_iter.next(),
+            "--event-log" => options.event_log = args_iter.next(),
+            _ => rest.push(arg),
+        }
+    }
+
+    *args = rest;
+    options
+}
+
+// Warnings and up by default, for everything but the game itself, which
+// also logs what it's doing at info level
+pub fn init_logging(options: &LogOptions) {
+    let mut builder = env_logger::Builder::new();
+    builder
+        .filter_level(LevelFilter::Warn)
+        .filter_module(env!("CARGO_CRATE_NAME"), LevelFilter::Info);
+    for target in [EVENTS, INPUT, AUDIO, RENDERING] {
+        builder.filter_module(target, LevelFilter::Info);
+    }
+
+    if let Ok(filter) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filter);
+    }
+    if let Some(filter) = &options.filter {
+        builder.parse_filters(filter);
+    }
+
+    builder.init();
+}
+
+// One line of the event log, like
+// `{"time_ms":1200,"kind":"EntityMoved","event":{"EntityMoved":{"entity":5,"from":[2,4]}}}`
+#[derive(Serialize)]
+struct EventRecord<'a> {
+    // Since the game started
+    time_ms: u128,
+    kind: EventKind,
+    event: &'a Event,
+}
+
+// The event log file, only there when asked for with --event-log
+pub struct EventLog {
+    writer: LineWriter<File>,
+}
+
+impl EventLog {
+    pub fn create(path: &str) -> io::Result<Self> {
+        Ok(EventLog {
+            writer: LineWriter::new(File::create(path)?),
+        })
+    }
+
+    pub fn record(&mut self, time_ms: u128, event: &Event) {
+        let record = EventRecord {
+            time_ms,
+            kind: event.kind(),
+            event,
+        };
+        let line = serde_json::to_string(&record).unwrap();
+        if let Err(error) = writeln!(self.writer, "{}", line) {
+            log::warn!(target: EVENTS, "Couldn't write to the event log: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::achievements::Achievement;
+    use crate::events::EntityMoved;
+
+    fn record(event: &Event) -> serde_json::Value {
+        let record = EventRecord {
+            time_ms: 1200,
+            kind: event.kind(),
+            event,
+        };
+        serde_json::to_value(&record).unwrap()
+    }
+
+    #[test]
+    fn records_events_as_structured_json() {
+        let mut world = hecs::World::new();
+        let entity = world.spawn(());
+        let moved = Event::EntityMoved(EntityMoved {
+            entity,
+            from: (2, 4),
+        });
+
+        assert_eq!(
+            record(&moved),
+            serde_json::json!({
+                "time_ms": 1200,
+                "kind": "EntityMoved",
+                "event": { "EntityMoved": { "entity": entity.id(), "from": [2, 4] } },
+            })
+        );
+        assert_eq!(
+            record(&Event::AchievementUnlocked(Achievement::NoUndo))["event"],
+            serde_json::json!({ "AchievementUnlocked": "no-undo" })
+        );
+        assert_eq!(record(&Event::LevelWon)["event"], "LevelWon");
+    }
+}
@@ -2,17 +2,34 @@
 // Rust sokoban
 // main.rs
 
+use ggez::input::gamepad::{gilrs, GamepadId};
 use ggez::{conf, event, Context, GameResult};
 use hecs::World;
 
-use std::path;
+use std::{path, process};
 
+use components::*;
+use resources::Resources;
+use schedule::{Schedule, Stage};
+use systems::input::GamepadEvent;
+
+mod achievements;
 mod components;
+mod config;
 mod constants;
+mod daily;
 mod entities;
 mod events;
+mod generator;
+mod levels;
+mod logging;
 mod map;
+mod pathfinding;
+mod resources;
+mod schedule;
+mod stats;
 mod systems;
+mod verifier;
 
 // ANCHOR: game
 // This struct will hold all our game state
@@ -20,43 +37,89 @@ mod systems;
 // things shortly.
 struct Game {
     world: World,
+    resources: Resources,
+    schedule: Schedule,
 }
 // ANCHOR_END: game
 
 // ANCHOR: handler
 impl event::EventHandler<ggez::GameError> for Game {
     fn update(&mut self, context: &mut Context) -> GameResult {
-        // Run input system
-        {
-            systems::input::run_input(&self.world, context);
+        for stage in Stage::UPDATE {
+            self.schedule
+                .run(stage, &mut self.world, &self.resources, context);
         }
 
-        // Run gameplay state
-        {
-            systems::gameplay::run_gameplay_state(&self.world);
+        Ok(())
+    }
+
+    fn draw(&mut self, context: &mut Context) -> GameResult {
+        // Render game entities
+        self.schedule
+            .run(Stage::Render, &mut self.world, &self.resources, context);
+
+        Ok(())
+    }
+
+    // Stats and achievements get saved every so often while playing, what
+    // was counted since goes in before the game closes
+    fn quit_event(&mut self, context: &mut Context) -> GameResult<bool> {
+        if let Ok(mut stats) = self.resources.get_mut::<stats::Stats>() {
+            if stats.changed {
+                stats::save_record(context, &stats.record);
+                stats.changed = false;
+            }
+        }
+        if let Ok(mut achievements) = self.resources.get_mut::<achievements::Achievements>() {
+            if achievements.changed {
+                achievements::save_record(context, &achievements.record);
+                achievements.changed = false;
+            }
         }
 
-        // Run events processing
+        Ok(false)
+    }
+
+    fn gamepad_button_down_event(
+        &mut self,
+        _context: &mut Context,
+        button: gilrs::Button,
+        _id: GamepadId,
+    ) -> GameResult {
+        if let Err(error) =
+            systems::input::handle_gamepad_event(&self.resources, GamepadEvent::ButtonDown(button))
         {
-            systems::events::run_process_events(&mut self.world, context);
+            log::error!("Gamepad input failed: {}", error);
         }
+        Ok(())
+    }
 
-        // Get and update time resource
+    fn gamepad_button_up_event(
+        &mut self,
+        _context: &mut Context,
+        button: gilrs::Button,
+        _id: GamepadId,
+    ) -> GameResult {
+        if let Err(error) =
+            systems::input::handle_gamepad_event(&self.resources, GamepadEvent::ButtonUp(button))
         {
-            let mut query = self.world.query::<&mut crate::components::Time>();
-            let time = query.iter().next().unwrap().1;
-            time.delta += context.time.delta();
+            log::error!("Gamepad input failed: {}", error);
         }
-
         Ok(())
     }
 
-    fn draw(&mut self, context: &mut Context) -> GameResult {
-        // Render game entities
+    fn gamepad_axis_event(
+        &mut self,
+        _context: &mut Context,
+        axis: gilrs::Axis,
+        value: f32,
+        _id: GamepadId,
+    ) -> GameResult {
+        if let Err(error) =
+            systems::input::handle_gamepad_event(&self.resources, GamepadEvent::Axis(axis, value))
         {
-            systems::rendering::run_rendering(&self.world, context);
+            log::error!("Gamepad input failed: {}", error);
         }
-
         Ok(())
     }
 }
@@ -64,7 +127,66 @@ impl event::EventHandler<ggez::GameError> for Game {
 
 // ANCHOR: main
 pub fn main() -> GameResult {
+    // `--log events=debug` turns up the logging for part of the game (as
+    // does RUST_LOG), `--event-log events.jsonl` writes down every event
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let log_options = logging::take_log_options(&mut args);
+    logging::init_logging(&log_options);
+
+    // `--disable audio` leaves out a system, see systems::create_schedule
+    let disabled_systems = take_disabled_systems(&mut args);
+
+    // `rust-sokoban generate --boxes 4 --seed 7` prints a new level
+    // instead of starting the game
+    if args.first().map(String::as_str) == Some("generate") {
+        let settings = match generator::GeneratorSettings::from_args(&args[1..]) {
+            Ok(settings) => settings,
+            Err(error) => {
+                eprintln!("{}", error);
+                process::exit(2);
+            }
+        };
+        match generator::generate(&settings) {
+            Ok(Some(level)) => {
+                println!("{}", level.map);
+                println!(
+                    "seed {}, {} pushes, branching {:.1}, difficulty {}",
+                    settings.seed,
+                    level.difficulty.pushes,
+                    level.difficulty.branching,
+                    level.difficulty.score()
+                );
+            }
+            Ok(None) => println!("No solvable level found for these settings"),
+            Err(error) => {
+                eprintln!("{}", error);
+                process::exit(2);
+            }
+        }
+        return Ok(());
+    }
+
+    // `rust-sokoban verify level.txt LURD` checks a solution for a level,
+    // the solution can also be given as a file. Exits with 1 for a wrong
+    // solution, and 2 if it couldn't be checked at all.
+    if args.first().map(String::as_str) == Some("verify") {
+        match verifier::verify_args(&args[1..]) {
+            Ok(verification) => {
+                println!("{}", verification);
+                if !verification.is_valid() {
+                    process::exit(1);
+                }
+            }
+            Err(error) => {
+                eprintln!("{}", error);
+                process::exit(2);
+            }
+        }
+        return Ok(());
+    }
+
     let mut world = World::new();
+    let mut resources = Resources::default();
 
     // Create a game context and event loop
     let context_builder = ggez::ContextBuilder::new("rust_sokoban", "sokoban")
@@ -74,18 +196,70 @@ pub fn main() -> GameResult {
 
     let (mut context, event_loop) = context_builder.build()?;
 
-    entities::create_gameplay(&mut world);
-    entities::create_time(&mut world);
-    entities::create_event_queue(&mut world);
-    entities::create_audio_store(&mut world);
+    resources.insert(config::load_config(&context));
+
+    resources.insert(Gameplay::default());
+    resources.insert(LevelRules::default());
+    resources.insert(Time::default());
+    resources.insert(GamepadInput::default());
+    resources.insert(PlayerPath::default());
+    resources.insert(History::default());
+    resources.insert(EventQueue::default());
+    resources.insert(AudioStore::default());
+    resources.insert(achievements::Achievements::new(achievements::load_record(
+        &context,
+    )));
+    resources.insert(schedule::Profiler::default());
+    resources.insert(stats::Stats::new(stats::load_record(&context)));
+    if let Some(path) = &log_options.event_log {
+        match logging::EventLog::create(path) {
+            Ok(event_log) => {
+                resources.insert(event_log);
+            }
+            Err(error) => log::warn!("Couldn't create the event log {}: {}", path, error),
+        }
+    }
+
+    // `rust-sokoban daily` plays today's daily challenge
+    if args.first().map(String::as_str) == Some("daily") {
+        daily::initialize_daily(&mut world, &mut resources, &mut context);
+    } else {
+        map::initialize_level(&mut world, &resources, &mut context);
 
-    map::initialize_level(&mut world, &mut context);
+        let index = levels::scan_levels(&context);
+        let progress = levels::load_progress(&context);
+        resources.insert(levels::LevelBrowser::new(index, progress));
+    }
 
     // Create the game state
-    let game = Game { world };
+    let mut schedule = systems::create_schedule();
+    for name in disabled_systems {
+        schedule.set_enabled(&name, false);
+    }
+    let game = Game {
+        world,
+        resources,
+        schedule,
+    };
     // Run the main event loop
     event::run(context, event_loop, game)
 }
 // ANCHOR_END: main
 
+fn take_disabled_systems(args: &mut Vec<String>) -> Vec<String> {
+    let mut disabled = Vec::new();
+    let mut rest = Vec::new();
+
+    let mut args_iter = std::mem::take(args).into_iter();
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            "--disable" => disabled.extend(args_iter.next()),
+            _ => rest.push(arg),
+        }
+    }
+
+    *args = rest;
+    disabled
+}
+
 /* ANCHOR_END: all */
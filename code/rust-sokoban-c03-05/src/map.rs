@@ -1,10 +1,23 @@
-use crate::components::{BoxColour, Position};
+use crate::components::{
+    AudioStore, BoxColour, Direction, EventQueue, LevelRules, Position, Renderable, Sound,
+    Teleporter,
+};
+use crate::config::load_toml;
 use crate::entities::*;
+use crate::events::{Event, LevelStarted};
+use crate::levels::fingerprint;
+use crate::logging::AUDIO;
+use crate::resources::Resources;
 use ggez::audio::Source;
+use ggez::graphics::Color;
 use ggez::Context;
-use hecs::World;
+use hecs::{Entity, World};
+use serde::Deserialize;
 
-pub fn initialize_level(world: &mut World, context: &mut Context) {
+use std::collections::HashMap;
+use std::time::Duration;
+
+pub fn initialize_level(world: &mut World, resources: &Resources, context: &mut Context) {
     const MAP: &str = "
     N N W W W W W W
     W W W . . . . W
@@ -17,13 +30,77 @@ pub fn initialize_level(world: &mut World, context: &mut Context) {
     W W W W W W W W
     ";
 
-    load_map(world, MAP.to_string());
-    load_sounds(world, context);
+    load_map(world, resources, MAP.to_string()).expect("the built-in level is valid");
+    resolve_images(world, context);
+    load_sounds(resources, context);
 }
 
-pub fn load_map(world: &mut World, map_string: String) {
+// Fails on anything it doesn't understand, which can leave part of the
+// level behind in the world
+pub fn load_map(
+    world: &mut World,
+    resources: &Resources,
+    map_string: String,
+) -> Result<(), String> {
+    // Red and blue are always available, levels can declare more colours
+    // with lines like `colour: G green #33aa44`, after which `GB` is a green
+    // box and `GS` a green spot. A `mode: pull` line makes it a reverse
+    // level, where boxes get pulled rather than pushed, and `music: calm`
+    // plays /music/calm.ogg (or .wav) while it's being played.
+    let mut colours: HashMap<char, BoxColour> = HashMap::from([
+        ('R', BoxColour::new("red", Color::from_rgb(200, 40, 40))),
+        ('B', BoxColour::new("blue", Color::from_rgb(40, 80, 200))),
+    ]);
+
     // read all lines
-    let rows: Vec<&str> = map_string.trim().split('\n').map(|x| x.trim()).collect();
+    let mut pull_mode = false;
+    let mut music = None;
+    let mut title = None;
+    let mut rows: Vec<&str> = Vec::new();
+    for line in map_string.trim().split('\n').map(|x| x.trim()) {
+        if let Some(declaration) = line.strip_prefix("colour:") {
+            let (letter, colour) = parse_colour(declaration)?;
+            colours.insert(letter, colour);
+        } else if let Some(level_title) = line.strip_prefix("title:") {
+            title = Some(level_title.trim().to_string());
+        } else if ["author:", "difficulty:"]
+            .iter()
+            .any(|key| line.starts_with(key))
+        {
+            // Level file metadata, only the level browser needs it
+        } else if let Some(track) = line.strip_prefix("music:") {
+            music = Some(track.trim().to_string());
+        } else if let Some(mode) = line.strip_prefix("mode:") {
+            pull_mode = match mode.trim() {
+                "push" => false,
+                "pull" => true,
+                mode => return Err(format!("unrecognized mode {}", mode)),
+            };
+        } else {
+            rows.push(line);
+        }
+    }
+
+    // Levels without music of their own get the default track
+    if let Ok(mut audio_store) = resources.get_mut::<AudioStore>() {
+        audio_store.level_music = music;
+    }
+
+    if let Ok(mut event_queue) = resources.get_mut::<EventQueue>() {
+        event_queue.events.push(Event::LevelStarted(LevelStarted {
+            fingerprint: fingerprint(&map_string),
+            title: title.unwrap_or_else(|| "untitled".to_string()),
+        }));
+    }
+
+    // Levels without a `mode:` line are plain push levels, whatever was
+    // played before
+    if let Ok(mut level_rules) = resources.get_mut::<LevelRules>() {
+        *level_rules = LevelRules { pull_mode };
+    }
+
+    // Teleporters by their label, e.g. both `T1` tiles end up under 1
+    let mut teleporters: HashMap<u8, Vec<Entity>> = HashMap::new();
 
     for (y, row) in rows.iter().enumerate() {
         let columns: Vec<&str> = row.split(' ').collect();
@@ -41,50 +118,245 @@ pub fn load_map(world: &mut World, map_string: String) {
                 "." => {
                     create_floor(world, position);
                 }
-                "W" => {
-                    create_floor(world, position);
-                    create_wall(world, position);
+                "I" => {
+                    create_ice(world, position);
+                }
+                "^" | "v" | "<" | ">" => {
+                    let direction = match *column {
+                        "^" => Direction::Up,
+                        "v" => Direction::Down,
+                        "<" => Direction::Left,
+                        _ => Direction::Right,
+                    };
+                    create_one_way(world, position, direction);
                 }
-                "P" => {
+                "H" => {
+                    // The floor shows once the hole is filled
                     create_floor(world, position);
-                    create_player(world, position);
+                    create_hole(world, position);
                 }
-                "BB" => {
+                "W" => {
                     create_floor(world, position);
-                    create_box(world, position, BoxColour::Blue);
+                    create_wall(world, position);
                 }
-                "RB" => {
+                "P" | "P1" => {
                     create_floor(world, position);
-                    create_box(world, position, BoxColour::Red);
+                    create_player(world, position, 1);
                 }
-                "BS" => {
+                "P2" => {
                     create_floor(world, position);
-                    create_box_spot(world, position, BoxColour::Blue);
+                    create_player(world, position, 2);
                 }
-                "RS" => {
+                "*S" => {
                     create_floor(world, position);
-                    create_box_spot(world, position, BoxColour::Red);
+                    create_box_spot(world, position, None);
                 }
                 "N" => (),
-                c => panic!("unrecognized map item {}", c),
+                c if parse_labelled(c).is_some() => {
+                    // Teleporters, keys, doors, pressure plates and gates are
+                    // followed by the label that ties them together, e.g. `K1`
+                    // opens `D1` and `T1` leads to the other `T1`
+                    let (kind, label) = parse_labelled(c).unwrap();
+                    match kind {
+                        'T' => {
+                            let teleporter = create_teleporter(world, position);
+                            teleporters.entry(label).or_default().push(teleporter);
+                        }
+                        'K' => {
+                            create_floor(world, position);
+                            create_key(world, position, label);
+                        }
+                        'D' => {
+                            create_floor(world, position);
+                            create_door(world, position, label);
+                        }
+                        'O' => {
+                            create_floor(world, position);
+                            create_pressure_plate(world, position, label);
+                        }
+                        'G' => {
+                            create_floor(world, position);
+                            create_gate(world, position, label);
+                        }
+                        _ => return Err(format!("unrecognized map item {}", c)),
+                    }
+                }
+                c => {
+                    // Anything else has to be a box or a spot of a known colour
+                    let mut chars = c.chars();
+                    let colour = chars.next().and_then(|letter| colours.get(&letter));
+                    match (colour, chars.as_str()) {
+                        (Some(colour), "B") => {
+                            create_floor(world, position);
+                            create_box(world, position, colour.clone());
+                        }
+                        (Some(colour), "S") => {
+                            create_floor(world, position);
+                            create_box_spot(world, position, Some(colour.clone()));
+                        }
+                        _ => return Err(format!("unrecognized map item {}", c)),
+                    }
+                }
             }
         }
     }
+
+    // Now that every teleporter exists, link each one to its partner
+    for (label, entities) in teleporters {
+        match entities.as_slice() {
+            [a, b] => {
+                world.insert_one(*a, Teleporter { pair: *b }).unwrap();
+                world.insert_one(*b, Teleporter { pair: *a }).unwrap();
+            }
+            _ => return Err(format!("teleporter T{} needs exactly two tiles", label)),
+        }
+    }
+
+    Ok(())
 }
 
-pub fn load_sounds(world: &mut World, context: &mut Context) {
-    let mut query = world.query::<&mut crate::components::AudioStore>();
-    let audio_store = query.iter().next().unwrap().1;
+// Picks the fallback images of anything whose own images are missing,
+// once, rather than looking for them every frame
+pub fn resolve_images(world: &mut World, context: &Context) {
+    let mut exists: HashMap<String, bool> = HashMap::new();
+    for (_, renderable) in world.query_mut::<&mut Renderable>() {
+        renderable.resolve_fallback(|path| {
+            *exists
+                .entry(path.to_string())
+                .or_insert_with(|| context.fs.exists(path))
+        });
+    }
+}
 
-    let sounds = ["correct", "incorrect", "wall"];
+// Splits a map item like `K1` into its kind and label
+fn parse_labelled(item: &str) -> Option<(char, u8)> {
+    let kind = item.chars().next()?;
+    let label = item[kind.len_utf8()..].parse().ok()?;
+    Some((kind, label))
+}
+
+// Parses the part after `colour:` in a colour declaration, that is the
+// letter used in the map, the colour name and optionally the tint to
+// use when there are no images for this colour, e.g. `G green #33aa44`.
+fn parse_colour(declaration: &str) -> Result<(char, BoxColour), String> {
+    let parts: Vec<&str> = declaration.split_whitespace().collect();
+
+    let (letter, name, tint) = match parts.as_slice() {
+        [letter, name] => (letter, name, Color::WHITE),
+        [letter, name, hex] => (letter, name, parse_hex_colour(hex)?),
+        _ => return Err(format!("invalid colour declaration {}", declaration)),
+    };
+
+    let mut letters = letter.chars();
+    match (letters.next(), letters.next()) {
+        (Some(letter), None) => Ok((letter, BoxColour::new(name, tint))),
+        _ => Err(format!(
+            "colour letter must be a single character, got {}",
+            letter
+        )),
+    }
+}
+
+fn parse_hex_colour(hex: &str) -> Result<Color, String> {
+    let rgb = hex
+        .strip_prefix('#')
+        .filter(|digits| digits.len() == 6)
+        .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+        .ok_or_else(|| format!("invalid colour {}, expected #rrggbb", hex))?;
+
+    Ok(Color::from_rgb(
+        (rgb >> 16) as u8,
+        (rgb >> 8) as u8,
+        rgb as u8,
+    ))
+}
+
+// Which sounds to play for which event, see /sounds/manifest.toml
+const SOUND_MANIFEST_PATH: &str = "/sounds/manifest.toml";
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct SoundEntry {
+    // One of these gets picked at random every time the sound plays
+    files: Vec<String>,
+    // How far the pitch may wander either way, 0.1 is up to 10%
+    pitch_jitter: f32,
+    volume: f32,
+    // Shortest time between two plays of the sound
+    cooldown_ms: u64,
+    // Turn the music down while it plays, best left off for sounds that
+    // play all the time
+    duck: bool,
+}
+
+impl Default for SoundEntry {
+    fn default() -> Self {
+        Self {
+            files: Vec::new(),
+            pitch_jitter: 0.0,
+            volume: 1.0,
+            cooldown_ms: 0,
+            duck: true,
+        }
+    }
+}
+
+// Loads every sound in the manifest. Files that are missing or can't be
+// loaded are skipped, so a sound without any files is simply silent.
+pub fn load_sounds(resources: &Resources, context: &mut Context) {
+    let mut audio_store = match resources.get_mut::<AudioStore>() {
+        Ok(audio_store) => audio_store,
+        Err(error) => {
+            log::warn!(target: AUDIO, "Couldn't load sounds: {}", error);
+            return;
+        }
+    };
+
+    let manifest: HashMap<String, SoundEntry> = load_toml(context, SOUND_MANIFEST_PATH);
+    if manifest.is_empty() {
+        log::warn!(target: AUDIO, "No sounds in {}", SOUND_MANIFEST_PATH);
+    }
+
+    for (name, entry) in manifest {
+        let variants: Vec<Source> = entry
+            .files
+            .iter()
+            .filter_map(|path| match Source::new(context, path) {
+                Ok(source) => Some(source),
+                Err(error) => {
+                    log::warn!(target: AUDIO, "Couldn't load sound {} for {}: {}", path, name, error);
+                    None
+                }
+            })
+            .collect();
+
+        if !variants.is_empty() {
+            audio_store.sounds.insert(
+                name,
+                Sound {
+                    variants,
+                    pitch_jitter: entry.pitch_jitter,
+                    volume: entry.volume,
+                    cooldown: Duration::from_millis(entry.cooldown_ms),
+                    last_played: None,
+                    duck: entry.duck,
+                },
+            );
+        }
+    }
+}
 
-    for sound in sounds.iter() {
-        let sound_name = sound.to_string();
-        let sound_path = format!("/sounds/{}.wav", sound_name);
-        let sound_source = Source::new(context, sound_path).expect("expected sound loaded");
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        audio_store
-            .sounds
-            .insert(sound_name, Box::new(sound_source));
+    #[test]
+    fn steps_and_pushes_leave_the_music_alone() {
+        let manifest: HashMap<String, SoundEntry> =
+            toml::from_str(include_str!("../resources/sounds/manifest.toml")).unwrap();
+        assert!(!manifest["footstep"].duck);
+        assert!(!manifest["push"].duck);
+        assert!(manifest["box-on-spot"].duck);
+        assert!(manifest["win"].duck);
     }
 }
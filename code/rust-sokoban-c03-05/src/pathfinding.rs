@@ -0,0 +1,398 @@
+use crate::components::Direction;
+use crate::config::Rules;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+// What the path finding needs to know about the map
+#[derive(Default)]
+pub struct Grid {
+    // Tiles nothing can move onto
+    pub blocked: HashSet<(u8, u8)>,
+    // Tiles anything that moves onto keeps sliding over
+    pub ice: HashSet<(u8, u8)>,
+    // Teleporter tiles and where they lead to
+    pub teleporters: HashMap<(u8, u8), (u8, u8)>,
+    // Tiles that can only be entered moving in the given direction
+    pub one_way: HashMap<(u8, u8), Direction>,
+    // Boxes that get dragged along when the player walks away from them,
+    // as they do in pull mode. Paths never step away from one.
+    pub dragged: HashSet<(u8, u8)>,
+}
+
+impl Grid {
+    // Whether something can move onto `tile` in `direction`
+    fn can_enter(&self, tile: (u8, u8), direction: Direction) -> bool {
+        !self.blocked.contains(&tile)
+            && self
+                .one_way
+                .get(&tile)
+                .is_none_or(|allowed| *allowed == direction)
+    }
+
+    // Where something that just moved onto `from` in `direction` ends up
+    // once it's done sliding over any ice, stopping before blocked tiles
+    // and `obstacle`, if any.
+    fn slide(&self, from: (u8, u8), direction: Direction, obstacle: Option<(u8, u8)>) -> (u8, u8) {
+        let mut current = from;
+
+        while self.ice.contains(&current) {
+            match direction.step(current) {
+                Some(next) if Some(next) != obstacle && self.can_enter(next, direction) => {
+                    current = next
+                }
+                _ => break,
+            }
+        }
+
+        current
+    }
+
+    // Where something standing on `from` ends up after going through a
+    // teleporter, if there is one and its exit is free.
+    fn teleport(&self, from: (u8, u8), obstacle: Option<(u8, u8)>) -> (u8, u8) {
+        match self.teleporters.get(&from) {
+            Some(exit) if Some(*exit) != obstacle && !self.blocked.contains(exit) => *exit,
+            _ => from,
+        }
+    }
+
+    // Whether stepping off `from` in `direction` leaves a dragged box behind
+    fn drags_box(&self, from: (u8, u8), direction: Direction) -> bool {
+        direction
+            .opposite()
+            .step(from)
+            .is_some_and(|behind| self.dragged.contains(&behind))
+    }
+
+    // Slides and teleports, the way an entity moves after each step
+    fn settle(&self, from: (u8, u8), direction: Direction, obstacle: Option<(u8, u8)>) -> (u8, u8) {
+        let stopped = self.slide(from, direction, obstacle);
+        self.teleport(stopped, obstacle)
+    }
+}
+
+// Shortest walk from `from` to `to` that avoids every blocked tile.
+// Returns the directions to take, or None if `to` can't be reached.
+pub fn find_walk_path(from: (u8, u8), to: (u8, u8), grid: &Grid) -> Option<Vec<Direction>> {
+    let mut came_from: HashMap<(u8, u8), ((u8, u8), Direction)> = HashMap::new();
+    let mut queue = VecDeque::from([from]);
+
+    while let Some(current) = queue.pop_front() {
+        if current == to {
+            return Some(rebuild_path(&came_from, from, to));
+        }
+
+        for direction in Direction::ALL {
+            if grid.drags_box(current, direction) {
+                continue;
+            }
+
+            let next = match direction.step(current) {
+                Some(next) if grid.can_enter(next, direction) => grid.settle(next, direction, None),
+                _ => continue,
+            };
+
+            if next == from || came_from.contains_key(&next) {
+                continue;
+            }
+
+            came_from.insert(next, (current, direction));
+            queue.push_back(next);
+        }
+    }
+
+    None
+}
+
+// Shortest sequence of moves that gets the box at `the_box` onto `to` by
+// pushing it, starting with the player at `player`. The player can walk
+// around freely in between pushes, but never through a blocked tile.
+// Only ever pushes a single box, so the path is valid under any rules
+// that allow pushing at all. In pull mode the box gets pulled instead.
+pub fn find_push_path(
+    player: (u8, u8),
+    the_box: (u8, u8),
+    to: (u8, u8),
+    grid: &Grid,
+    rules: &Rules,
+    pull_mode: bool,
+) -> Option<Vec<Direction>> {
+    if rules.max_push_chain == 0 && !pull_mode {
+        return None;
+    }
+
+    // Every state is a (player, box) pair, which is small enough for the
+    // map sizes we deal with to simply search all of them.
+    type State = ((u8, u8), (u8, u8));
+
+    let start: State = (player, the_box);
+    let mut came_from: HashMap<State, (State, Direction)> = HashMap::new();
+    let mut queue = VecDeque::from([start]);
+
+    while let Some(current) = queue.pop_front() {
+        let (player, the_box) = current;
+        if the_box == to {
+            return Some(rebuild_path(&came_from, start, current));
+        }
+
+        for direction in Direction::ALL {
+            if grid.drags_box(player, direction) {
+                continue;
+            }
+
+            let next_player = match direction.step(player) {
+                Some(next) if grid.can_enter(next, direction) => next,
+                _ => continue,
+            };
+
+            // Walking into the box pushes it along. In pull mode boxes are
+            // in the way instead, but walking away from the box drags it onto
+            // the tile the player just left.
+            let entered = if pull_mode {
+                if next_player == the_box {
+                    continue;
+                }
+                let behind = direction.opposite().step(player);
+                if behind == Some(the_box) && grid.can_enter(player, direction) {
+                    Some(player)
+                } else {
+                    None
+                }
+            } else if next_player == the_box {
+                match direction.step(the_box) {
+                    Some(next) if grid.can_enter(next, direction) => Some(next),
+                    _ => continue,
+                }
+            } else {
+                None
+            };
+
+            // The box moves first, so it stops right in front of (or behind)
+            // the player
+            let next_box = match entered {
+                Some(entered) => {
+                    let obstacle = if pull_mode { Some(next_player) } else { None };
+                    let stopped = grid.slide(entered, direction, obstacle);
+                    if rules.teleport_boxes {
+                        grid.teleport(stopped, Some(next_player))
+                    } else {
+                        stopped
+                    }
+                }
+                None => the_box,
+            };
+            let next_player = grid.settle(next_player, direction, Some(next_box));
+
+            let next = (next_player, next_box);
+            if next == start || came_from.contains_key(&next) {
+                continue;
+            }
+
+            came_from.insert(next, (current, direction));
+            queue.push_back(next);
+        }
+    }
+
+    None
+}
+
+fn rebuild_path<T: Copy + Eq + std::hash::Hash>(
+    came_from: &HashMap<T, (T, Direction)>,
+    start: T,
+    end: T,
+) -> Vec<Direction> {
+    let mut path = Vec::new();
+    let mut current = end;
+
+    while current != start {
+        let (previous, direction) = came_from[&current];
+        path.push(direction);
+        current = previous;
+    }
+
+    path.reverse();
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::Direction::*;
+
+    // A grid from rows of tiles, `#` is blocked, `I` ice and `^`, `v`, `<`
+    // and `>` are one way, `B` is a box that gets dragged along, anything
+    // else is free
+    fn grid(rows: &[&str]) -> Grid {
+        let mut grid = Grid::default();
+        for (y, row) in rows.iter().enumerate() {
+            for (x, tile) in row.chars().enumerate() {
+                let position = (x as u8, y as u8);
+                match tile {
+                    '#' => {
+                        grid.blocked.insert(position);
+                    }
+                    'I' => {
+                        grid.ice.insert(position);
+                    }
+                    'B' => {
+                        grid.blocked.insert(position);
+                        grid.dragged.insert(position);
+                    }
+                    '^' | 'v' | '<' | '>' => {
+                        let direction = match tile {
+                            '^' => Up,
+                            'v' => Down,
+                            '<' => Left,
+                            _ => Right,
+                        };
+                        grid.one_way.insert(position, direction);
+                    }
+                    _ => (),
+                }
+            }
+        }
+        grid
+    }
+
+    #[test]
+    fn walks_the_shortest_way_around_walls() {
+        let grid = grid(&["#####", "#...#", "#.#.#", "#...#", "#####"]);
+        let path = find_walk_path((1, 1), (3, 3), &grid).unwrap();
+        assert_eq!(path.len(), 4);
+        assert_eq!(find_walk_path((1, 1), (1, 1), &grid), Some(vec![]));
+    }
+
+    #[test]
+    fn cant_walk_to_blocked_or_closed_off_tiles() {
+        let grid = grid(&["#####", "#.#.#", "#####"]);
+        assert_eq!(find_walk_path((1, 1), (3, 1), &grid), None);
+        assert_eq!(find_walk_path((1, 1), (2, 1), &grid), None);
+    }
+
+    #[test]
+    fn slides_over_ice() {
+        let grid = grid(&["######", "#.II.#", "######"]);
+        assert_eq!(find_walk_path((1, 1), (4, 1), &grid), Some(vec![Right]));
+        // There's no stopping on the ice
+        assert_eq!(find_walk_path((1, 1), (3, 1), &grid), None);
+    }
+
+    #[test]
+    fn goes_through_one_way_tiles_only_one_way() {
+        let grid = grid(&["#####", "#.>.#", "#####"]);
+        assert_eq!(
+            find_walk_path((1, 1), (3, 1), &grid),
+            Some(vec![Right, Right])
+        );
+        assert_eq!(find_walk_path((3, 1), (1, 1), &grid), None);
+    }
+
+    #[test]
+    fn walks_through_teleporters() {
+        let mut grid = grid(&["#####", "#..##", "#####", "##..#", "#####"]);
+        grid.teleporters.insert((2, 1), (2, 3));
+        grid.teleporters.insert((2, 3), (2, 1));
+        assert_eq!(
+            find_walk_path((1, 1), (3, 3), &grid),
+            Some(vec![Right, Right])
+        );
+    }
+
+    #[test]
+    fn never_walks_away_from_a_box_in_pull_mode() {
+        let mut room = grid(&["#####", "#B..#", "#...#", "#####"]);
+        assert_eq!(
+            find_walk_path((2, 1), (3, 1), &room),
+            Some(vec![Down, Right, Up])
+        );
+
+        let corridor = grid(&["#####", "#B..#", "#####"]);
+        assert_eq!(find_walk_path((2, 1), (3, 1), &corridor), None);
+
+        // Boxes that stay put can be walked away from
+        room.dragged.clear();
+        assert_eq!(find_walk_path((2, 1), (3, 1), &room), Some(vec![Right]));
+    }
+
+    #[test]
+    fn pulls_one_box_without_dragging_another() {
+        // The only way to the box goes away from the other one
+        let mut room = grid(&["#######", "#B....#", "#.#...#", "#######"]);
+        let rules = Rules::default();
+        assert_eq!(
+            find_push_path((2, 1), (3, 2), (4, 2), &room, &rules, true),
+            None
+        );
+
+        room.dragged.clear();
+        let path = find_push_path((2, 1), (3, 2), (4, 2), &room, &rules, true).unwrap();
+        assert_eq!(path[0], Right);
+    }
+
+    #[test]
+    fn walks_around_the_box_to_push_it() {
+        let grid = grid(&["#####", "#...#", "#...#", "#...#", "#...#", "#####"]);
+        let path = find_push_path((2, 3), (2, 2), (2, 4), &grid, &Rules::default(), false).unwrap();
+        assert_eq!(path.len(), 6);
+        assert_eq!(path[4..], [Down, Down]);
+    }
+
+    #[test]
+    fn cant_push_a_box_out_of_a_corner() {
+        let grid = grid(&["#####", "#...#", "#...#", "#####"]);
+        let rules = Rules::default();
+        assert_eq!(
+            find_push_path((2, 2), (1, 1), (3, 2), &grid, &rules, false),
+            None
+        );
+    }
+
+    #[test]
+    fn pulls_the_box_in_pull_mode() {
+        let grid = grid(&["#######", "#.....#", "#######"]);
+        let rules = Rules::default();
+        assert_eq!(
+            find_push_path((3, 1), (4, 1), (2, 1), &grid, &rules, true),
+            Some(vec![Left, Left])
+        );
+        assert_eq!(
+            find_push_path((3, 1), (4, 1), (2, 1), &grid, &rules, false),
+            None
+        );
+    }
+
+    #[test]
+    fn cant_push_when_the_rules_dont_allow_it() {
+        let grid = grid(&["#####", "#...#", "#####"]);
+        let rules = Rules {
+            max_push_chain: 0,
+            ..Rules::default()
+        };
+        assert_eq!(
+            find_push_path((1, 1), (2, 1), (3, 1), &grid, &rules, false),
+            None
+        );
+    }
+
+    #[test]
+    fn boxes_go_through_teleporters_if_the_rules_say_so() {
+        let mut grid = grid(&["#######", "#.....#", "#######", "#.....#", "#######"]);
+        grid.teleporters.insert((3, 1), (4, 3));
+        grid.teleporters.insert((4, 3), (3, 1));
+
+        let rules = Rules::default();
+        assert_eq!(
+            find_push_path((1, 1), (2, 1), (4, 3), &grid, &rules, false),
+            Some(vec![Right])
+        );
+
+        let rules = Rules {
+            teleport_boxes: false,
+            ..Rules::default()
+        };
+        assert_eq!(
+            find_push_path((1, 1), (2, 1), (4, 3), &grid, &rules, false),
+            None
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use std::any::{type_name, Any, TypeId};
+use std::cell::{Ref, RefCell, RefMut};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum ResourceError {
+    // Nothing of that type was inserted
+    Missing(&'static str),
+    // Someone else is using it, mutably or while it's wanted mutably
+    Borrowed(&'static str),
+}
+
+impl fmt::Display for ResourceError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResourceError::Missing(name) => write!(fmt, "no {} resource", name),
+            ResourceError::Borrowed(name) => write!(fmt, "{} resource already in use", name),
+        }
+    }
+}
+
+// Things there is only one of in the game, like the config or the
+// gameplay state, kept next to the world by their type. Several systems
+// can look at a resource at the same time, or one can change it, which is
+// checked as they ask for it.
+#[derive(Default)]
+pub struct Resources {
+    resources: HashMap<TypeId, RefCell<Box<dyn Any>>>,
+}
+
+impl Resources {
+    // Returns the resource of the same type that was there before, if any
+    pub fn insert<T: 'static>(&mut self, resource: T) -> Option<T> {
+        self.resources
+            .insert(TypeId::of::<T>(), RefCell::new(Box::new(resource)))
+            .map(|old| *old.into_inner().downcast::<T>().unwrap())
+    }
+
+    pub fn contains<T: 'static>(&self) -> bool {
+        self.resources.contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn get<T: 'static>(&self) -> Result<Ref<'_, T>, ResourceError> {
+        let resource = self
+            .resources
+            .get(&TypeId::of::<T>())
+            .ok_or(ResourceError::Missing(type_name::<T>()))?;
+        let resource = resource
+            .try_borrow()
+            .map_err(|_| ResourceError::Borrowed(type_name::<T>()))?;
+        Ok(Ref::map(resource, |resource| {
+            resource.downcast_ref::<T>().unwrap()
+        }))
+    }
+
+    pub fn get_mut<T: 'static>(&self) -> Result<RefMut<'_, T>, ResourceError> {
+        let resource = self
+            .resources
+            .get(&TypeId::of::<T>())
+            .ok_or(ResourceError::Missing(type_name::<T>()))?;
+        let resource = resource
+            .try_borrow_mut()
+            .map_err(|_| ResourceError::Borrowed(type_name::<T>()))?;
+        Ok(RefMut::map(resource, |resource| {
+            resource.downcast_mut::<T>().unwrap()
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Score(u32);
+
+    #[test]
+    fn inserting_again_replaces_the_resource() {
+        let mut resources = Resources::default();
+        assert!(!resources.contains::<Score>());
+        assert_eq!(resources.insert(Score(1)), None);
+        assert!(resources.contains::<Score>());
+        assert_eq!(resources.insert(Score(2)), Some(Score(1)));
+        assert_eq!(*resources.get::<Score>().unwrap(), Score(2));
+    }
+
+    #[test]
+    fn changes_are_kept() {
+        let mut resources = Resources::default();
+        resources.insert(Score(1));
+        resources.get_mut::<Score>().unwrap().0 += 1;
+        assert_eq!(resources.get::<Score>().unwrap().0, 2);
+    }
+
+    #[test]
+    fn missing_resources_are_errors() {
+        let resources = Resources::default();
+        assert!(matches!(
+            resources.get::<Score>(),
+            Err(ResourceError::Missing(_))
+        ));
+        assert!(matches!(
+            resources.get_mut::<Score>(),
+            Err(ResourceError::Missing(_))
+        ));
+    }
+
+    #[test]
+    fn resources_can_be_looked_at_together_but_changed_alone() {
+        let mut resources = Resources::default();
+        resources.insert(Score(1));
+
+        {
+            let _first = resources.get::<Score>().unwrap();
+            assert!(resources.get::<Score>().is_ok());
+            assert!(matches!(
+                resources.get_mut::<Score>(),
+                Err(ResourceError::Borrowed(_))
+            ));
+        }
+
+        let _changing = resources.get_mut::<Score>().unwrap();
+        assert!(matches!(
+            resources.get::<Score>(),
+            Err(ResourceError::Borrowed(_))
+        ));
+        assert!(matches!(
+            resources.get_mut::<Score>(),
+            Err(ResourceError::Borrowed(_))
+        ));
+    }
+
+    #[test]
+    fn other_resources_can_be_changed_at_the_same_time() {
+        let mut resources = Resources::default();
+        resources.insert(Score(1));
+        resources.insert(String::from("level"));
+
+        let _score = resources.get_mut::<Score>().unwrap();
+        assert!(resources.get_mut::<String>().is_ok());
+    }
+
+    #[test]
+    fn errors_name_the_resource() {
+        let resources = Resources::default();
+        let error = resources.get::<Score>().err().unwrap();
+        assert!(error.to_string().starts_with("no "));
+        assert!(error.to_string().ends_with("Score resource"));
+    }
+}
@@ -0,0 +1,266 @@
+use ggez::Context;
+use hecs::World;
+
+use crate::resources::{ResourceError, Resources};
+
+use std::time::{Duration, Instant};
+
+// When in the frame a system runs. Everything up to audio runs on update,
+// render runs on draw.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    Input,
+    Logic,
+    PostLogic,
+    Audio,
+    Render,
+}
+
+impl Stage {
+    pub const UPDATE: [Stage; 4] = [Stage::Input, Stage::Logic, Stage::PostLogic, Stage::Audio];
+}
+
+type Run = Box<dyn FnMut(&mut World, &Resources, &mut Context) -> Result<(), ResourceError>>;
+
+pub struct System {
+    name: &'static str,
+    stage: Stage,
+    // Systems in the same stage that have to run before this one
+    after: Vec<&'static str>,
+    enabled: bool,
+    run: Run,
+    // Smoothed over the last frames, so it can be read
+    average_time: Duration,
+}
+
+impl System {
+    pub fn new(
+        name: &'static str,
+        stage: Stage,
+        run: impl FnMut(&mut World, &Resources, &mut Context) -> Result<(), ResourceError> + 'static,
+    ) -> Self {
+        System {
+            name,
+            stage,
+            after: Vec::new(),
+            enabled: true,
+            run: Box::new(run),
+            average_time: Duration::ZERO,
+        }
+    }
+
+    pub fn after(mut self, name: &'static str) -> Self {
+        self.after.push(name);
+        self
+    }
+}
+
+// How long a system has been taking, for the profiler
+pub struct Timing {
+    pub name: &'static str,
+    pub stage: Stage,
+    pub enabled: bool,
+    pub average_time: Duration,
+}
+
+// Whether the profiler overlay is showing, toggled with F3, and the
+// timings it shows, kept up to date by the schedule. Systems can be
+// switched on and off from the overlay, which the schedule picks up the
+// next time it runs.
+#[derive(Default)]
+pub struct Profiler {
+    pub open: bool,
+    pub timings: Vec<Timing>,
+    pub selected: usize,
+    pub toggle: Option<&'static str>,
+}
+
+// The systems of the game, in the order they run: by stage, then in the
+// order they were added, unless that would run a system before one it
+// should come after
+#[derive(Default)]
+pub struct Schedule {
+    systems: Vec<System>,
+    sorted: bool,
+}
+
+impl Schedule {
+    pub fn add(&mut self, system: System) {
+        if self.systems.iter().any(|other| other.name == system.name) {
+            panic!("there already is a system called {}", system.name);
+        }
+        self.systems.push(system);
+        self.sorted = false;
+    }
+
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) {
+        match self.systems.iter_mut().find(|system| system.name == name) {
+            Some(system) => system.enabled = enabled,
+            None => log::warn!("Unknown system {}, leaving it be", name),
+        }
+    }
+
+    // A system that can't get hold of a resource is skipped for the frame,
+    // the others still run
+    pub fn run(
+        &mut self,
+        stage: Stage,
+        world: &mut World,
+        resources: &Resources,
+        context: &mut Context,
+    ) {
+        if !self.sorted {
+            self.sort();
+        }
+
+        let toggle = resources
+            .get_mut::<Profiler>()
+            .ok()
+            .and_then(|mut profiler| profiler.toggle.take());
+        if let Some(name) = toggle {
+            let enabled = self
+                .systems
+                .iter()
+                .any(|system| system.name == name && system.enabled);
+            log::info!(
+                "Turning system {} {}",
+                name,
+                if enabled { "off" } else { "on" }
+            );
+            self.set_enabled(name, !enabled);
+        }
+
+        for system in self.systems.iter_mut() {
+            if system.stage != stage || !system.enabled {
+                continue;
+            }
+
+            let start = Instant::now();
+            if let Err(error) = (system.run)(world, resources, context) {
+                log::error!("System {} failed: {}", system.name, error);
+            }
+            system.average_time = (system.average_time * 9 + start.elapsed()) / 10;
+        }
+
+        if let Ok(mut profiler) = resources.get_mut::<Profiler>() {
+            profiler.timings = self
+                .systems
+                .iter()
+                .map(|system| Timing {
+                    name: system.name,
+                    stage: system.stage,
+                    enabled: system.enabled,
+                    average_time: system.average_time,
+                })
+                .collect();
+        }
+    }
+
+    // Takes the first system that has everything it comes after in place,
+    // over and over again
+    fn sort(&mut self) {
+        let mut left = std::mem::take(&mut self.systems);
+        left.sort_by_key(|system| system.stage);
+
+        for system in left.iter() {
+            for name in system.after.iter() {
+                match left.iter().find(|other| other.name == *name) {
+                    Some(other) if other.stage == system.stage => (),
+                    Some(_) => panic!("{} can't come after {} in another stage", system.name, name),
+                    None => panic!("{} comes after unknown system {}", system.name, name),
+                }
+            }
+        }
+
+        while !left.is_empty() {
+            let ready = left
+                .iter()
+                .position(|system| {
+                    system
+                        .after
+                        .iter()
+                        .all(|name| !left.iter().any(|other| other.name == *name))
+                })
+                .unwrap_or_else(|| panic!("{} and others wait on each other", left[0].name));
+            self.systems.push(left.remove(ready));
+        }
+
+        self.sorted = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn system(name: &'static str, stage: Stage) -> System {
+        System::new(name, stage, |_, _, _| Ok(()))
+    }
+
+    fn sorted(mut schedule: Schedule) -> Vec<&'static str> {
+        schedule.sort();
+        schedule.systems.iter().map(|system| system.name).collect()
+    }
+
+    #[test]
+    fn runs_by_stage_then_in_the_order_added() {
+        let mut schedule = Schedule::default();
+        schedule.add(system("audio", Stage::Audio));
+        schedule.add(system("input", Stage::Input));
+        schedule.add(system("gameplay", Stage::Logic));
+        schedule.add(system("browser", Stage::Input));
+        schedule.add(system("rendering", Stage::Render));
+
+        assert_eq!(
+            sorted(schedule),
+            ["input", "browser", "gameplay", "audio", "rendering"]
+        );
+    }
+
+    #[test]
+    fn runs_systems_after_what_they_come_after() {
+        let mut schedule = Schedule::default();
+        schedule.add(system("daily", Stage::Logic).after("gameplay"));
+        schedule.add(system("gameplay", Stage::Logic).after("switches"));
+        schedule.add(system("other", Stage::Logic));
+        schedule.add(system("switches", Stage::Logic));
+
+        assert_eq!(sorted(schedule), ["other", "switches", "gameplay", "daily"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "wait on each other")]
+    fn finds_cycles() {
+        let mut schedule = Schedule::default();
+        schedule.add(system("a", Stage::Logic).after("c"));
+        schedule.add(system("b", Stage::Logic).after("a"));
+        schedule.add(system("c", Stage::Logic).after("b"));
+        sorted(schedule);
+    }
+
+    #[test]
+    #[should_panic(expected = "in another stage")]
+    fn only_orders_within_a_stage() {
+        let mut schedule = Schedule::default();
+        schedule.add(system("input", Stage::Input));
+        schedule.add(system("gameplay", Stage::Logic).after("input"));
+        sorted(schedule);
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown system")]
+    fn only_comes_after_known_systems() {
+        let mut schedule = Schedule::default();
+        schedule.add(system("gameplay", Stage::Logic).after("nothing"));
+        sorted(schedule);
+    }
+
+    #[test]
+    fn ignores_unknown_systems_when_switching() {
+        let mut schedule = Schedule::default();
+        schedule.add(system("gameplay", Stage::Logic));
+        schedule.set_enabled("nothing", false);
+        schedule.set_enabled("gameplay", false);
+        assert!(!schedule.systems[0].enabled);
+    }
+}
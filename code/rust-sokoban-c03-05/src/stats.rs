@@ -0,0 +1,233 @@
+use crate::config::{load_toml, save_toml};
+use ggez::Context;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::time::Duration;
+
+const RECORD_PATH: &str = "/stats.toml";
+const CSV_PATH: &str = "/stats.csv";
+
+// Counts piled up by playing, for a single level or for several together
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LevelStats {
+    pub title: String,
+    // Times the level was loaded, and solved
+    pub attempts: u32,
+    pub completions: u32,
+    pub moves: u32,
+    pub pushes: u32,
+    pub undos: u32,
+    pub restarts: u32,
+    pub wall_bumps: u32,
+    pub wrong_spots: u32,
+    pub time_played_ms: u64,
+}
+
+impl LevelStats {
+    // Solved attempts out of all of them, from 0 to 1
+    pub fn completion_rate(&self) -> f32 {
+        if self.attempts == 0 {
+            0.0
+        } else {
+            self.completions as f32 / self.attempts as f32
+        }
+    }
+
+    pub fn time_played(&self) -> Duration {
+        Duration::from_millis(self.time_played_ms)
+    }
+
+    fn add(&mut self, other: &LevelStats) {
+        self.attempts += other.attempts;
+        self.completions += other.completions;
+        self.moves += other.moves;
+        self.pushes += other.pushes;
+        self.undos += other.undos;
+        self.restarts += other.restarts;
+        self.wall_bumps += other.wall_bumps;
+        self.wrong_spots += other.wrong_spots;
+        self.time_played_ms += other.time_played_ms;
+    }
+}
+
+// Stats of every level played, by level fingerprint, saved to stats.toml
+// in the user config directory
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct StatsRecord {
+    pub levels: BTreeMap<String, LevelStats>,
+}
+
+// How long at most stats go unsaved while playing
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+// The stats screen, toggled with Tab, and what's counted for it
+pub struct Stats {
+    pub record: StatsRecord,
+    // Everything since the game started
+    pub session: LevelStats,
+    // Fingerprint of the level being played
+    pub level: Option<String>,
+    // Whether this attempt at it was counted as a completion already, as
+    // it can be won again after undoing
+    pub completed: bool,
+    pub open: bool,
+    // First level shown in the breakdown
+    pub scroll: usize,
+    // Whether anything changed since the last save, and when that was
+    pub changed: bool,
+    pub saved_at: Duration,
+}
+
+impl Stats {
+    pub fn new(record: StatsRecord) -> Self {
+        Stats {
+            record,
+            session: LevelStats::default(),
+            level: None,
+            completed: false,
+            open: false,
+            scroll: 0,
+            changed: false,
+            saved_at: Duration::ZERO,
+        }
+    }
+
+    pub fn start_level(&mut self, fingerprint: &str, title: &str) {
+        let level = self
+            .record
+            .levels
+            .entry(fingerprint.to_string())
+            .or_default();
+        level.title = title.to_string();
+        self.level = Some(fingerprint.to_string());
+        self.completed = false;
+        self.count(|stats| stats.attempts += 1);
+    }
+
+    // Counts a completion, once per attempt
+    pub fn complete(&mut self) {
+        if !self.completed {
+            self.completed = true;
+            self.count(|stats| stats.completions += 1);
+        }
+    }
+
+    // Counts something both for this session and the level being played
+    pub fn count(&mut self, update: impl Fn(&mut LevelStats)) {
+        update(&mut self.session);
+        if let Some(level) = self.level.as_ref() {
+            update(self.record.levels.get_mut(level).unwrap());
+        }
+        self.changed = true;
+    }
+
+    pub fn all_time(&self) -> LevelStats {
+        let mut total = LevelStats::default();
+        for level in self.record.levels.values() {
+            total.add(level);
+        }
+        total
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from(
+            "level,title,attempts,completions,completion_rate,moves,pushes,undos,restarts,wall_bumps,wrong_spots,seconds_played\n",
+        );
+        for (fingerprint, level) in self.record.levels.iter() {
+            csv += &format!(
+                "{},\"{}\",{},{},{:.2},{},{},{},{},{},{},{:.1}\n",
+                fingerprint,
+                level.title.replace('"', "\"\""),
+                level.attempts,
+                level.completions,
+                level.completion_rate(),
+                level.moves,
+                level.pushes,
+                level.undos,
+                level.restarts,
+                level.wall_bumps,
+                level.wrong_spots,
+                level.time_played().as_secs_f32()
+            );
+        }
+        csv
+    }
+}
+
+pub fn load_record(context: &Context) -> StatsRecord {
+    load_toml(context, RECORD_PATH)
+}
+
+pub fn save_record(context: &Context, record: &StatsRecord) {
+    save_toml(context, RECORD_PATH, record)
+}
+
+// Writes the per level stats to stats.csv, next to stats.toml
+pub fn export_csv(context: &Context, stats: &Stats) {
+    let written = context
+        .fs
+        .create(CSV_PATH)
+        .and_then(|mut file| Ok(file.write_all(stats.to_csv().as_bytes())?));
+
+    match written {
+        Ok(()) => log::info!(
+            "Exported stats to {}",
+            context.fs.user_config_dir().join("stats.csv").display()
+        ),
+        Err(error) => log::warn!("Couldn't export stats: {}", error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_for_the_session_and_the_level() {
+        let mut stats = Stats::new(StatsRecord::default());
+        stats.start_level("abc", "First");
+        stats.count(|stats| stats.moves += 3);
+        stats.start_level("def", "Second");
+        stats.count(|stats| stats.completions += 1);
+
+        assert_eq!(stats.session.attempts, 2);
+        assert_eq!(stats.session.moves, 3);
+        assert_eq!(stats.record.levels["abc"].moves, 3);
+        assert_eq!(stats.record.levels["def"].completions, 1);
+        assert_eq!(stats.all_time().completion_rate(), 0.5);
+        assert!(stats.changed);
+    }
+
+    #[test]
+    fn exports_one_csv_line_per_level() {
+        let mut stats = Stats::new(StatsRecord::default());
+        stats.start_level("abc", "First");
+        stats.count(|stats| {
+            stats.completions += 1;
+            stats.time_played_ms += 1500
+        });
+
+        let csv = stats.to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("level,title,attempts"));
+        assert_eq!(lines[1], "abc,\"First\",1,1,1.00,0,0,0,0,0,0,1.5");
+    }
+
+    #[test]
+    fn quotes_titles_in_the_csv() {
+        let mut stats = Stats::new(StatsRecord::default());
+        stats.start_level("abc", "The \"big\" one, again");
+
+        let csv = stats.to_csv();
+        assert!(csv
+            .lines()
+            .nth(1)
+            .unwrap()
+            .starts_with("abc,\"The \"\"big\"\" one, again\",1,"));
+    }
+}
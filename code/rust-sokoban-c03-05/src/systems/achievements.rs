@@ -0,0 +1,97 @@
+use ggez::Context;
+use hecs::World;
+
+use crate::achievements::*;
+use crate::components::*;
+use crate::events::*;
+use crate::resources::{ResourceError, Resources};
+
+// Counts towards and unlocks achievements as the game goes
+pub fn track_achievements<C>(
+    _world: &mut World,
+    resources: &Resources,
+    _context: &mut C,
+    event: &Event,
+    fired: &mut Vec<Event>,
+) -> Result<(), ResourceError> {
+    // Achievements are optional, e.g. when verifying levels
+    if !resources.contains::<Achievements>() {
+        return Ok(());
+    }
+    let now = resources.get::<Time>()?.delta;
+    let pushes = resources.get::<Gameplay>()?.pushes_count;
+    let mut achievements = resources.get_mut::<Achievements>()?;
+
+    let mut earned = Vec::new();
+    match event {
+        Event::LevelStarted(LevelStarted { fingerprint, .. }) => {
+            achievements.start_level(fingerprint, now);
+            return Ok(());
+        }
+        Event::MoveUndone => {
+            achievements.used_undo = true;
+            return Ok(());
+        }
+        Event::BoxPlacedOnSpot(BoxPlacedOnSpot {
+            is_correct_spot: false,
+        }) => {
+            achievements.record.wrong_spots += 1;
+            if achievements.record.wrong_spots >= WRONG_SPOTS_FOR_STUBBORN {
+                earned.push(Achievement::Stubborn);
+            }
+        }
+        Event::LevelWon => {
+            let level = achievements.level.clone();
+            achievements.record.solved_levels.insert(level);
+            if achievements.record.solved_levels.len() >= LEVELS_FOR_COLLECTOR {
+                earned.push(Achievement::Collector);
+            }
+            if !achievements.used_undo {
+                earned.push(Achievement::NoUndo);
+            }
+            if now.saturating_sub(achievements.started) <= SPEEDY_TIME {
+                earned.push(Achievement::Speedy);
+            }
+            // Pushing several boxes at once can even beat the optimum
+            if achievements
+                .optimal_pushes
+                .is_some_and(|optimal| pushes as usize <= optimal)
+            {
+                earned.push(Achievement::Optimal);
+            }
+        }
+        _ => return Ok(()),
+    }
+
+    let mut unlocked = false;
+    for achievement in earned {
+        if achievements.unlock(achievement, now) {
+            fired.push(Event::AchievementUnlocked(achievement));
+            unlocked = true;
+        }
+    }
+
+    achievements.changed = true;
+    achievements.save_now |= unlocked || matches!(event, Event::LevelWon);
+    Ok(())
+}
+
+// Saves the achievements right away when one got unlocked or a level was
+// won, anything else counted in between at most every so often
+pub fn run_achievements(resources: &Resources, context: &Context) -> Result<(), ResourceError> {
+    if !resources.contains::<Achievements>() {
+        return Ok(());
+    }
+    let mut achievements = resources.get_mut::<Achievements>()?;
+
+    let now = context.time.time_since_start();
+    if achievements.changed
+        && (achievements.save_now || now.saturating_sub(achievements.saved_at) >= SAVE_INTERVAL)
+    {
+        save_record(context, &achievements.record);
+        achievements.changed = false;
+        achievements.save_now = false;
+        achievements.saved_at = now;
+    }
+    Ok(())
+}
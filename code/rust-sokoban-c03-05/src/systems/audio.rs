@@ -0,0 +1,116 @@
+use ggez::{input::keyboard::KeyCode, Context};
+use hecs::World;
+
+use crate::components::*;
+use crate::config::{save_audio_config, Config};
+use crate::events::*;
+use crate::levels::LevelBrowser;
+use crate::resources::{ResourceError, Resources};
+use crate::stats::Stats;
+
+// How much the volume keys change the master volume by
+const VOLUME_STEP: f32 = 0.1;
+
+// M mutes and unmutes, - and = turn the master volume down and up. Changes
+// are saved to the audio section of the config, and applied to everything
+// in the audio store. Also keeps the music going, paused while the level
+// browser or the stats screen is open.
+pub fn run_audio(resources: &Resources, context: &mut Context) -> Result<(), ResourceError> {
+    let mut config = resources.get_mut::<Config>()?;
+    let audio = &mut config.audio;
+
+    let keyboard = &context.keyboard;
+    let changed = if keyboard.is_key_just_pressed(KeyCode::M) {
+        audio.muted = !audio.muted;
+        true
+    } else if keyboard.is_key_just_pressed(KeyCode::Minus) {
+        audio.master_volume = step_volume(audio.master_volume, -VOLUME_STEP);
+        true
+    } else if keyboard.is_key_just_pressed(KeyCode::Equals) {
+        audio.master_volume = step_volume(audio.master_volume, VOLUME_STEP);
+        true
+    } else {
+        false
+    };
+
+    if changed {
+        save_audio_config(context, audio);
+    }
+
+    let mut audio_store = resources.get_mut::<AudioStore>()?;
+
+    let in_menu = resources
+        .get::<LevelBrowser>()
+        .is_ok_and(|browser| browser.open)
+        || resources.get::<Stats>().is_ok_and(|stats| stats.open);
+    audio_store.update_music(context, &config.audio, in_menu);
+    audio_store.apply_volumes(context, &config.audio);
+    Ok(())
+}
+
+// Rounded, so that stepping up and down lands on the same volumes
+fn step_volume(volume: f32, step: f32) -> f32 {
+    ((volume + step).clamp(0.0, 1.0) * 10.0).round() / 10.0
+}
+
+// Plays the sound that goes with an event, from the sound manifest
+pub fn play_event_sound(
+    world: &mut World,
+    resources: &Resources,
+    context: &mut Context,
+    event: &Event,
+    _: &mut Vec<Event>,
+) -> Result<(), ResourceError> {
+    let sound = match event {
+        Event::LevelStarted(_) => return Ok(()),
+        Event::PlayerHitObstacle => "wall",
+        Event::EntityMoved(EntityMoved { entity, .. }) => {
+            if world.get::<&Player>(*entity).is_err() {
+                return Ok(());
+            }
+            "footstep"
+        }
+        // The entity moved event for the same entity already checked
+        // where it ended up, all that's left is the thud of a box
+        // sliding into something.
+        Event::EntitySlid(EntitySlid {
+            entity,
+            hit_obstacle,
+        }) => {
+            if !hit_obstacle || world.get::<&Box>(*entity).is_err() {
+                return Ok(());
+            }
+            "wall"
+        }
+        Event::EntityTeleported(_) => "teleport",
+        Event::KeyCollected => "key",
+        Event::DoorUnlocked => "door",
+        Event::GateOpened | Event::GateClosed => "gate",
+        Event::HoleFilled => "hole",
+        Event::BoxPushed => "push",
+        Event::BoxRemovedFromSpot => "box-off-spot",
+        Event::BoxPlacedOnSpot(BoxPlacedOnSpot { is_correct_spot }) => {
+            if *is_correct_spot {
+                "box-on-spot"
+            } else {
+                "wrong-spot"
+            }
+        }
+        Event::LevelWon => {
+            let config = resources.get::<Config>()?;
+            resources
+                .get_mut::<AudioStore>()?
+                .play_jingle(context, &config.audio, "win");
+            return Ok(());
+        }
+        Event::MoveUndone | Event::LevelRestarted => "undo",
+        Event::MenuNavigated => "menu",
+        Event::AchievementUnlocked(_) => "achievement",
+    };
+
+    let config = resources.get::<Config>()?;
+    resources
+        .get_mut::<AudioStore>()?
+        .play_sound(context, &config.audio, sound);
+    Ok(())
+}
@@ -0,0 +1,167 @@
+use ggez::{
+    input::{gamepad::gilrs::Button, keyboard::KeyCode},
+    Context,
+};
+use hecs::{Entity, World};
+
+use crate::achievements::Achievements;
+use crate::components::*;
+use crate::events::Event;
+use crate::levels::{read_level, save_progress, LevelBrowser};
+use crate::map::{load_map, resolve_images};
+use crate::resources::{ResourceError, Resources};
+use crate::stats::Stats;
+
+pub fn run_browser(
+    world: &mut World,
+    resources: &Resources,
+    context: &Context,
+) -> Result<(), ResourceError> {
+    // There's no browser without a levels folder
+    if !resources.contains::<LevelBrowser>() {
+        return Ok(());
+    }
+
+    let mut level_to_play = None;
+    let mut navigated = false;
+
+    {
+        let mut browser = resources.get_mut::<LevelBrowser>()?;
+
+        // Remember the level being played once it's solved
+        let won = matches!(resources.get::<Gameplay>()?.state, GameplayState::Won);
+        if let Some(playing) = browser.playing.clone().filter(|_| won) {
+            if browser.progress.solved.insert(playing) {
+                save_progress(context, &browser.progress);
+            }
+        }
+
+        let in_stats = resources.get::<Stats>().is_ok_and(|stats| stats.open);
+
+        // Y on the gamepad opens the browser too, then the D-pad chooses, A
+        // plays and B goes back
+        let keyboard = &context.keyboard;
+        let toggle = keyboard.is_key_just_pressed(KeyCode::L)
+            || resources.get::<GamepadInput>()?.was_pressed(Button::North);
+        if toggle && !browser.index.levels.is_empty() && !in_stats {
+            browser.open = !browser.open;
+            navigated = true;
+        }
+
+        let (directions, buttons) = if browser.open {
+            resources.get_mut::<GamepadInput>()?.take_presses()
+        } else {
+            Default::default()
+        };
+        let pressed_up =
+            keyboard.is_key_just_pressed(KeyCode::Up) || directions.contains(&Direction::Up);
+        let pressed_down =
+            keyboard.is_key_just_pressed(KeyCode::Down) || directions.contains(&Direction::Down);
+
+        if !browser.open {
+            // Nothing else to do
+        } else if keyboard.is_key_just_pressed(KeyCode::Key1) {
+            browser.sort_by = browser.sort_by.next();
+            navigated = true;
+        } else if keyboard.is_key_just_pressed(KeyCode::Key2) {
+            browser.solved_filter = browser.solved_filter.next();
+            browser.selected = 0;
+            navigated = true;
+        } else if keyboard.is_key_just_pressed(KeyCode::Key3) {
+            browser.next_size_filter();
+            browser.selected = 0;
+            navigated = true;
+        } else if keyboard.is_key_just_pressed(KeyCode::Key4) {
+            browser.next_author_filter();
+            browser.selected = 0;
+            navigated = true;
+        } else {
+            let visible = browser.visible().len();
+            if pressed_up && browser.selected > 0 {
+                browser.selected -= 1;
+                navigated = true;
+            } else if pressed_down && browser.selected + 1 < visible {
+                browser.selected += 1;
+                navigated = true;
+            } else if buttons.contains(&Button::East) {
+                browser.open = false;
+                navigated = true;
+            } else if keyboard.is_key_just_pressed(KeyCode::Return)
+                || buttons.contains(&Button::South)
+            {
+                level_to_play = browser
+                    .visible()
+                    .get(browser.selected)
+                    .map(|level| level.path.clone());
+                navigated = true;
+            }
+        }
+    }
+
+    if let Some(path) = level_to_play {
+        play_level(world, resources, context, &path)?;
+    }
+
+    // After playing a level, which empties the queue
+    if navigated {
+        let mut event_queue = resources.get_mut::<EventQueue>()?;
+        event_queue.events.push(Event::MenuNavigated);
+    }
+
+    Ok(())
+}
+
+// Swaps the current level for the one in the given file
+fn play_level(
+    world: &mut World,
+    resources: &Resources,
+    context: &Context,
+    path: &str,
+) -> Result<(), ResourceError> {
+    let map = match read_level(context, path) {
+        Some(map) => map,
+        None => {
+            log::warn!("Couldn't read level {}", path);
+            return Ok(());
+        }
+    };
+
+    // Try the level out on its own first, so a broken one doesn't take the
+    // current level with it
+    if let Err(error) = load_map(&mut World::new(), &Resources::default(), map.clone()) {
+        log::warn!("Couldn't load level {}: {}", path, error);
+        return Ok(());
+    }
+
+    // Everything on the map goes, the resources stay but start over
+    let on_map: Vec<Entity> = world
+        .query::<&Position>()
+        .iter()
+        .map(|(entity, _)| entity)
+        .collect();
+    for entity in on_map {
+        world.despawn(entity).unwrap();
+    }
+
+    *resources.get_mut::<Gameplay>()? = Gameplay::default();
+    *resources.get_mut::<History>()? = History::default();
+    *resources.get_mut::<PlayerPath>()? = PlayerPath::default();
+    resources.get_mut::<EventQueue>()?.events.clear();
+
+    load_map(world, resources, map).expect("the level loaded before");
+    resolve_images(world, context);
+
+    let mut browser = resources.get_mut::<LevelBrowser>()?;
+    // The index has the fewest pushes for levels the solver could rate
+    if let Ok(mut achievements) = resources.get_mut::<Achievements>() {
+        achievements.optimal_pushes = browser
+            .index
+            .levels
+            .iter()
+            .find(|level| level.path == path)
+            .and_then(|level| level.pushes);
+    }
+    browser.open = false;
+    browser.playing = Some(path.to_string());
+    Ok(())
+}
@@ -0,0 +1,39 @@
+use crate::components::*;
+use crate::daily::{format_day, save_record, save_result, Daily};
+use crate::resources::{ResourceError, Resources};
+use ggez::Context;
+
+// Records today's daily challenge as solved the first time it gets won,
+// keeping the streak going, and puts together a result to share, which
+// gets shown under the level and saved to a file.
+pub fn run_daily(resources: &Resources, context: &Context) -> Result<(), ResourceError> {
+    if !resources.contains::<Daily>() {
+        return Ok(());
+    }
+    let mut daily = resources.get_mut::<Daily>()?;
+
+    let gameplay = resources.get::<Gameplay>()?;
+    if !matches!(gameplay.state, GameplayState::Won) || daily.solved_today() {
+        return Ok(());
+    }
+
+    let streak = daily.current_streak() + 1;
+    daily.record.last_solved = Some(daily.day);
+    daily.record.streak = streak;
+    daily.record.best_streak = daily.record.best_streak.max(streak);
+    save_record(context, &daily.record);
+
+    let seconds = resources.get::<Time>()?.delta.as_secs();
+    let result = format!(
+        "Rust Sokoban daily {}: {} moves, {} pushes, {}:{:02}, streak {}",
+        format_day(daily.day),
+        gameplay.moves_count,
+        gameplay.pushes_count,
+        seconds / 60,
+        seconds % 60,
+        streak
+    );
+    save_result(context, &result);
+    daily.result = Some(result);
+    Ok(())
+}
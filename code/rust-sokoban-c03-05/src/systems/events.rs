@@ -1,78 +1,323 @@
 use crate::components::*;
 use crate::events::*;
+use crate::logging::{EventLog, EVENTS};
+use crate::resources::{ResourceError, Resources};
+use crate::systems::achievements::track_achievements;
+use crate::systems::audio::play_event_sound;
+use crate::systems::stats::track_stats;
 use ggez::Context;
 use hecs::World;
 
-use std::collections::HashMap;
-
-pub fn run_process_events(world: &mut World, context: &mut Context) {
-    let events = {
-        let mut query = world.query::<&mut crate::components::EventQueue>();
-        let events = query
-            .iter()
-            .next()
-            .unwrap()
-            .1
-            .events
-            .drain(..)
-            .collect::<Vec<_>>();
-
-        events
+// Who gets which events. This is also the order they get them in, so
+// anything that needs the effects of another subscriber goes after it.
+pub fn create_event_bus() -> EventBus<Context> {
+    let mut event_bus = EventBus::default();
+    subscribe_reactions(&mut event_bus);
+    event_bus.subscribe_all(play_event_sound);
+    event_bus
+}
+
+// Everything that reacts to events without needing ggez, which is all but
+// the sounds
+fn subscribe_reactions<C>(event_bus: &mut EventBus<C>) {
+    event_bus.subscribe_all(log_event);
+    event_bus.subscribe_all(record_event);
+    event_bus.subscribe(&[EventKind::EntityMoved], check_box_moved);
+    event_bus.subscribe(&[EventKind::EntityTeleported], fade_in_teleported);
+    event_bus.subscribe(
+        &[
+            EventKind::LevelStarted,
+            EventKind::MoveUndone,
+            EventKind::BoxPlacedOnSpot,
+            EventKind::LevelWon,
+        ],
+        track_achievements,
+    );
+    event_bus.subscribe(
+        &[
+            EventKind::LevelStarted,
+            EventKind::EntityMoved,
+            EventKind::BoxPushed,
+            EventKind::MoveUndone,
+            EventKind::LevelRestarted,
+            EventKind::PlayerHitObstacle,
+            EventKind::BoxPlacedOnSpot,
+            EventKind::LevelWon,
+        ],
+        track_stats,
+    );
+}
+
+pub fn run_process_events(
+    world: &mut World,
+    resources: &Resources,
+    context: &mut Context,
+    event_bus: &EventBus<Context>,
+) -> Result<(), ResourceError> {
+    let events = resources
+        .get_mut::<EventQueue>()?
+        .events
+        .drain(..)
+        .collect::<Vec<_>>();
+
+    event_bus.dispatch(world, resources, context, events);
+    Ok(())
+}
+
+fn log_event<C>(
+    _world: &mut World,
+    _resources: &Resources,
+    _context: &mut C,
+    event: &Event,
+    _: &mut Vec<Event>,
+) -> Result<(), ResourceError> {
+    match event {
+        Event::AchievementUnlocked(achievement) => {
+            log::info!(target: EVENTS, "Achievement unlocked: {}", achievement.title())
+        }
+        _ => log::debug!(target: EVENTS, "{:?}", event),
+    }
+    Ok(())
+}
+
+// Writes the event to the event log, if there is one
+fn record_event<C>(
+    _world: &mut World,
+    resources: &Resources,
+    _context: &mut C,
+    event: &Event,
+    _: &mut Vec<Event>,
+) -> Result<(), ResourceError> {
+    if let Ok(mut event_log) = resources.get_mut::<EventLog>() {
+        event_log.record(resources.get::<Time>()?.delta.as_millis(), event);
+    }
+    Ok(())
+}
+
+// An entity was just moved, check if it was a box and fire more events
+// if it's been pushed off or on a spot
+fn check_box_moved<C>(
+    world: &mut World,
+    _resources: &Resources,
+    _context: &mut C,
+    event: &Event,
+    fired: &mut Vec<Event>,
+) -> Result<(), ResourceError> {
+    let (entity, from) = match event {
+        Event::EntityMoved(EntityMoved { entity, from }) => (*entity, *from),
+        _ => return Ok(()),
+    };
+
+    let the_box = match world.get::<&Box>(entity) {
+        Ok(the_box) => the_box,
+        Err(_) => return Ok(()),
     };
+    let box_position = *world.get::<&Position>(entity).unwrap();
 
-    let mut new_events = Vec::new();
+    fired.push(Event::BoxPushed);
 
     let mut query = world.query::<(&Position, &BoxSpot)>();
-    let box_spots_by_position: HashMap<(u8, u8), &BoxSpot> = query
-        .iter()
-        .map(|(_, t)| ((t.0.x, t.0.y), t.1))
-        .collect::<HashMap<_, _>>();
-
-    let mut query = world.query::<&mut AudioStore>();
-    let audio_store = query.iter().next().unwrap().1;
-
-    for event in events {
-        println!("New event: {:?}", event);
-
-        match event {
-            Event::PlayerHitObstacle => {
-                // play sound here
-                audio_store.play_sound(context, "wall");
-            }
-            Event::EntityMoved(EntityMoved { entity }) => {
-                // An entity was just moved, check if it was a box and fire
-                // more events if it's been moved on a spot.
-                if let Ok(the_box) = world.get::<&Box>(entity) {
-                    if let Ok(box_position) = world.get::<&Position>(entity) {
-                        // Check if there is a spot on this position, and if there
-                        // is if it's the correct or incorrect type
-                        if let Some(box_spot) =
-                            box_spots_by_position.get(&(box_position.x, box_position.y))
-                        {
-                            new_events.push(Event::BoxPlacedOnSpot(BoxPlacedOnSpot {
-                                is_correct_spot: (box_spot.colour == the_box.colour),
-                            }));
-                        }
-                    }
-                }
-            }
-            Event::BoxPlacedOnSpot(BoxPlacedOnSpot { is_correct_spot }) => {
-                // play sound here
-                let sound = if is_correct_spot {
-                    "correct"
-                } else {
-                    "incorrect"
-                };
-
-                audio_store.play_sound(context, sound);
-            }
+    for (_, (position, box_spot)) in query.iter() {
+        if (position.x, position.y) == from {
+            fired.push(Event::BoxRemovedFromSpot);
+        }
+
+        // Check if there is a spot on this position, and if there
+        // is if it's the correct or incorrect type
+        if position.x == box_position.x && position.y == box_position.y {
+            fired.push(Event::BoxPlacedOnSpot(BoxPlacedOnSpot {
+                is_correct_spot: box_spot.accepts(&the_box),
+            }));
+        }
+    }
+    Ok(())
+}
+
+// Mark whatever came out of a teleporter, so it gets faded in
+fn fade_in_teleported<C>(
+    world: &mut World,
+    resources: &Resources,
+    _context: &mut C,
+    event: &Event,
+    _: &mut Vec<Event>,
+) -> Result<(), ResourceError> {
+    let entity = match event {
+        Event::EntityTeleported(EntityTeleported { entity }) => *entity,
+        _ => return Ok(()),
+    };
+
+    let at = resources.get::<Time>()?.delta;
+    world.insert_one(entity, Teleported { at }).unwrap();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::achievements::{Achievement, AchievementRecord, Achievements};
+    use crate::config::Config;
+    use crate::entities::{create_box, create_box_spot};
+    use crate::map::load_map;
+    use crate::stats::{Stats, StatsRecord};
+    use crate::systems::input::move_player;
+    use ggez::graphics::Color;
+
+    fn resources() -> Resources {
+        let mut resources = Resources::default();
+        resources.insert(Time::default());
+        resources.insert(Gameplay::default());
+        resources.insert(Achievements::new(AchievementRecord::default()));
+        let mut stats = Stats::new(StatsRecord::default());
+        stats.start_level("level", "Level");
+        resources.insert(stats);
+        resources
+    }
+
+    #[test]
+    fn box_moves_are_counted_in_the_same_frame() {
+        let mut world = World::new();
+        let resources = resources();
+        let red = BoxColour::new("red", Color::RED);
+        let blue = BoxColour::new("blue", Color::BLUE);
+        let the_box = create_box(&mut world, Position { x: 2, y: 1, z: 0 }, red);
+        create_box_spot(&mut world, Position { x: 2, y: 1, z: 0 }, Some(blue));
+
+        let mut event_bus = EventBus::default();
+        subscribe_reactions(&mut event_bus);
+        let moved = Event::EntityMoved(EntityMoved {
+            entity: the_box,
+            from: (1, 1),
+        });
+        event_bus.dispatch(&mut world, &resources, &mut (), vec![moved]);
+
+        // The push and the wrong spot are fired by the move, and handled
+        // right after it
+        let stats = resources.get::<Stats>().unwrap();
+        assert_eq!(stats.session.pushes, 1);
+        assert_eq!(stats.session.wrong_spots, 1);
+        let achievements = resources.get::<Achievements>().unwrap();
+        assert_eq!(achievements.record.wrong_spots, 1);
+    }
+
+    #[test]
+    fn boxes_sliding_onto_a_spot_are_placed_on_it() {
+        let mut world = World::new();
+        let mut resources = resources();
+        resources.insert(Config::default());
+        resources.insert(LevelRules::default());
+        resources.insert(History::default());
+        resources.insert(EventQueue::default());
+        let map = "W P BB I I BS W".to_string();
+        load_map(&mut world, &resources, map).unwrap();
+        let (player, _) = world.query::<&Player>().iter().next().unwrap();
+
+        let mut events = Vec::new();
+        move_player(
+            &world,
+            &resources,
+            player,
+            Direction::Right,
+            false,
+            false,
+            &mut events,
+        )
+        .unwrap();
+
+        // Where the box ended up is looked at once the move is handled,
+        // after it slid
+        let mut event_bus = EventBus::default();
+        subscribe_reactions(&mut event_bus);
+        event_bus.subscribe_all(|_, _, seen: &mut Vec<String>, event, _| {
+            seen.push(format!("{:?}", event));
+            Ok(())
+        });
+        let mut seen = Vec::new();
+        event_bus.dispatch(&mut world, &resources, &mut seen, events);
+        assert!(seen.contains(&format!(
+            "{:?}",
+            Event::BoxPlacedOnSpot(BoxPlacedOnSpot {
+                is_correct_spot: true
+            })
+        )));
+    }
+
+    #[test]
+    fn levels_are_completed_once_per_attempt() {
+        let mut world = World::new();
+        let resources = resources();
+
+        let mut event_bus = EventBus::default();
+        subscribe_reactions(&mut event_bus);
+
+        // Undoing the last move and making it again wins the level again
+        let events = vec![Event::LevelWon, Event::MoveUndone, Event::LevelWon];
+        event_bus.dispatch(&mut world, &resources, &mut (), events);
+        {
+            let stats = resources.get::<Stats>().unwrap();
+            assert_eq!(stats.session.completions, 1);
+            assert_eq!(stats.record.levels["level"].completion_rate(), 1.0);
         }
+
+        let started = Event::LevelStarted(LevelStarted {
+            fingerprint: "level".to_string(),
+            title: "Level".to_string(),
+        });
+        event_bus.dispatch(
+            &mut world,
+            &resources,
+            &mut (),
+            vec![started, Event::LevelWon],
+        );
+        let stats = resources.get::<Stats>().unwrap();
+        assert_eq!(stats.session.attempts, 2);
+        assert_eq!(stats.session.completions, 2);
     }
 
-    // Finally add events back into the world
-    {
-        let mut query = world.query::<&mut EventQueue>();
-        let event_queue = query.iter().next().unwrap().1;
-        event_queue.events.append(&mut new_events);
+    #[test]
+    fn achievements_go_by_the_level_started() {
+        let mut world = World::new();
+        let resources = resources();
+        resources.get_mut::<Achievements>().unwrap().optimal_pushes = Some(4);
+        resources.get_mut::<Gameplay>().unwrap().pushes_count = 3;
+
+        let mut event_bus = EventBus::default();
+        subscribe_reactions(&mut event_bus);
+        let started = Event::LevelStarted(LevelStarted {
+            fingerprint: "abc".to_string(),
+            title: "Level".to_string(),
+        });
+        event_bus.dispatch(
+            &mut world,
+            &resources,
+            &mut (),
+            vec![started, Event::LevelWon],
+        );
+
+        // Pushing two boxes at once took fewer pushes than the optimum
+        let achievements = resources.get::<Achievements>().unwrap();
+        assert!(achievements.record.solved_levels.contains("abc"));
+        assert!(achievements
+            .record
+            .unlocked
+            .contains(Achievement::Optimal.id()));
+    }
+
+    #[test]
+    fn other_entities_moving_are_not_pushes() {
+        let mut world = World::new();
+        let resources = resources();
+        let entity = world.spawn((Position { x: 2, y: 1, z: 0 },));
+
+        let mut event_bus = EventBus::default();
+        subscribe_reactions(&mut event_bus);
+        let moved = Event::EntityMoved(EntityMoved {
+            entity,
+            from: (1, 1),
+        });
+        event_bus.dispatch(&mut world, &resources, &mut (), vec![moved]);
+
+        let stats = resources.get::<Stats>().unwrap();
+        assert_eq!(stats.session.pushes, 0);
+        assert_eq!(stats.session.moves, 0);
     }
 }
@@ -1,11 +1,14 @@
 use crate::components::*;
+use crate::events::Event;
+use crate::resources::{ResourceError, Resources};
 use hecs::World;
 
 use std::collections::HashMap;
 
-pub fn run_gameplay_state(world: &World) {
-    // get all boxes indexed by position
-    let mut query = world.query::<(&Position, &Box)>();
+pub fn run_gameplay_state(world: &World, resources: &Resources) -> Result<(), ResourceError> {
+    // get all boxes indexed by position, leaving out those that
+    // ended up in a hole
+    let mut query = world.query::<(&Position, &Box)>().without::<&Hidden>();
     let boxes_by_position: HashMap<(u8, u8), &Box> = query
         .iter()
         .map(|(_, t)| ((t.0.x, t.0.y), t.1))
@@ -18,7 +21,7 @@ pub fn run_gameplay_state(world: &World) {
         .iter()
         .map(|(_, (position, box_spot))| {
             if let Some(the_box) = boxes_by_position.get(&(position.x, position.y)) {
-                if box_spot.colour == the_box.colour {
+                if box_spot.accepts(the_box) {
                     0
                 } else {
                     1
@@ -32,10 +35,18 @@ pub fn run_gameplay_state(world: &World) {
         .sum();
 
     // If we made it this far, then all box spots have boxes on them, and the
-    // game has been won
-    if boxes_out_of_position == 0 {
-        let mut query = world.query::<&mut Gameplay>();
-        let gameplay = query.iter().next().unwrap().1;
-        gameplay.state = GameplayState::Won;
+    // game has been won. Undoing a move can take us back to playing.
+    let mut gameplay = resources.get_mut::<Gameplay>()?;
+    let was_won = matches!(gameplay.state, GameplayState::Won);
+    gameplay.state = if boxes_out_of_position == 0 {
+        GameplayState::Won
+    } else {
+        GameplayState::Playing
+    };
+
+    if boxes_out_of_position == 0 && !was_won {
+        let mut event_queue = resources.get_mut::<EventQueue>()?;
+        event_queue.events.push(Event::LevelWon);
     }
+    Ok(())
 }
@@ -1,16 +1,407 @@
-use ggez::{input::keyboard::KeyCode, Context};
+use ggez::{
+    input::{
+        gamepad::gilrs::{Axis, Button},
+        keyboard::{KeyCode, KeyMods},
+        mouse::MouseButton,
+    },
+    Context,
+};
 use hecs::{Entity, World};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
 use crate::components::*;
+use crate::config::Config;
 use crate::constants::*;
 use crate::events::*;
+use crate::levels::LevelBrowser;
+use crate::logging::INPUT;
+use crate::pathfinding::{find_push_path, find_walk_path, Grid};
+use crate::resources::{ResourceError, Resources};
+use crate::stats::Stats;
+
+// Direction keys for each player (up, down, left, right). Player 1 can
+// also use the gamepad and the mouse.
+const PLAYER_KEYS: [[KeyCode; 4]; 2] = [
+    [KeyCode::Up, KeyCode::Down, KeyCode::Left, KeyCode::Right],
+    [KeyCode::W, KeyCode::S, KeyCode::A, KeyCode::D],
+];
+
+#[derive(Debug)]
+pub enum GamepadEvent {
+    ButtonDown(Button),
+    ButtonUp(Button),
+    Axis(Axis, f32),
+}
+
+pub fn run_input(
+    world: &World,
+    resources: &Resources,
+    context: &mut Context,
+) -> Result<(), ResourceError> {
+    // Take what was pressed on the gamepad since the last update
+    let (gamepad_directions, gamepad_actions) = {
+        let mut gamepad = resources.get_mut::<GamepadInput>()?;
+        gamepad.buttons.clear();
+        (
+            std::mem::take(&mut gamepad.just_pressed),
+            std::mem::take(&mut gamepad.actions),
+        )
+    };
+
+    // The level browser and the stats screen take the keyboard and the
+    // gamepad while they're open
+    if resources
+        .get::<LevelBrowser>()
+        .is_ok_and(|browser| browser.open)
+        || resources.get::<Stats>().is_ok_and(|stats| stats.open)
+    {
+        return Ok(());
+    }
 
-pub fn run_input(world: &World, context: &mut Context) {
-    let mut to_move: Vec<(Entity, KeyCode)> = Vec::new();
     let mut events = Vec::new();
 
+    if context.mouse.button_just_pressed(MouseButton::Left) {
+        handle_click(world, resources, context)?;
+    }
+
+    let action = if context.keyboard.is_key_just_pressed(KeyCode::Z) {
+        Some(Action::Undo)
+    } else if context.keyboard.is_key_just_pressed(KeyCode::R) {
+        Some(Action::Restart)
+    } else {
+        gamepad_actions.first().copied()
+    };
+
+    if let Some(action) = action {
+        log::debug!(target: INPUT, "{:?}", action);
+
+        let event = match action {
+            Action::Undo => undo(world, resources)?.then_some(Event::MoveUndone),
+            Action::Restart => restart(world, resources)?.then_some(Event::LevelRestarted),
+        };
+        if let Some(event) = event {
+            let mut event_queue = resources.get_mut::<EventQueue>()?;
+            event_queue.events.push(event);
+        }
+        return Ok(());
+    }
+
+    let mut players: Vec<(Entity, u8)> = world
+        .query::<&Player>()
+        .iter()
+        .map(|(entity, player)| (entity, player.number))
+        .collect();
+    players.sort_by_key(|(_, number)| *number);
+
+    for (player, number) in players {
+        let keys = match PLAYER_KEYS.get(number as usize - 1) {
+            Some(keys) => keys,
+            None => continue,
+        };
+        let gamepad_directions: &[Direction] = if number == 1 {
+            &gamepad_directions
+        } else {
+            &[]
+        };
+
+        let direct = next_direction(world, resources, context, player, keys, gamepad_directions)?;
+        let (direction, is_repeat) =
+            match next_move(resources, number, direct, context.time.delta())? {
+                Some(next_move) => next_move,
+                None => continue,
+            };
+
+        let pull = context.keyboard.is_mod_active(KeyMods::SHIFT);
+        log::debug!(
+            target: INPUT,
+            "Player {} moves {:?}, repeat {}, pull {}",
+            number,
+            direction,
+            is_repeat,
+            pull
+        );
+        move_player(
+            world,
+            resources,
+            player,
+            direction,
+            is_repeat,
+            pull,
+            &mut events,
+        )?;
+    }
+
+    // Finally add events back into the world
+    {
+        let mut event_queue = resources.get_mut::<EventQueue>()?;
+        event_queue.events.append(&mut events);
+    }
+
+    Ok(())
+}
+
+// Moves the player one tile in `direction`, along with anything it pushes
+// (or pulls, if `pull` is set and the rules allow it). Also used to replay
+// solutions, so it doesn't look at any input itself.
+pub fn move_player(
+    world: &World,
+    resources: &Resources,
+    player: Entity,
+    direction: Direction,
+    is_repeat: bool,
+    pull: bool,
+    events: &mut Vec<Event>,
+) -> Result<(), ResourceError> {
+    let stop_repeat_before_push = resources.get::<Config>()?.input.stop_repeat_before_push;
+
+    let mut to_move = collect_moves(world, resources, player, direction, pull, events)?;
+
+    // Anything moved besides the player means a push (or pull), which we don't
+    // want to happen just because a key is being held down.
+    if is_repeat && stop_repeat_before_push && to_move.len() > 1 {
+        to_move.clear();
+        world.get::<&mut KeyRepeat>(player).unwrap().stopped = true;
+    }
+
+    // Something got in the way of the path we were walking, so give up on it
+    if to_move.is_empty() {
+        if world.get::<&Player>(player).unwrap().number == 1 {
+            clear_player_path(resources)?;
+        }
+        return Ok(());
+    }
+
+    // Update gameplay moves
+    save_snapshot(world, resources)?;
+    {
+        let mut gameplay = resources.get_mut::<Gameplay>()?;
+        gameplay.moves_count += 1;
+        if to_move.len() > 1 {
+            gameplay.pushes_count += 1;
+        }
+    }
+
+    // Now actually move what needs to be moved
+    for entity in to_move.iter().copied() {
+        let mut position = world.get::<&mut Position>(entity).unwrap();
+        let from = (position.x, position.y);
+
+        match direction {
+            Direction::Up => position.y -= 1,
+            Direction::Down => position.y += 1,
+            Direction::Left => position.x -= 1,
+            Direction::Right => position.x += 1,
+        }
+
+        // Fire an event for the entity that just moved
+        events.push(Event::EntityMoved(EntityMoved { entity, from }));
+    }
+
+    // Anything that ended up on ice keeps sliding, then goes through a
+    // teleporter if it stopped on one. Go front to back, so that whatever
+    // was pushing stops right behind what it pushed.
+    for entity in to_move.iter().rev() {
+        slide(world, *entity, direction, events);
+        teleport(world, resources, *entity, events)?;
+        fill_hole(world, *entity, events);
+    }
+
+    Ok(())
+}
+
+// Drops a box that ended up on an open hole into it
+fn fill_hole(world: &World, entity: Entity, events: &mut Vec<Event>) {
+    if world.get::<&Box>(entity).is_err() {
+        return;
+    }
+
+    let position = *world.get::<&Position>(entity).unwrap();
+    for (_, (hole_position, hole)) in world.query::<(&Position, &mut Hole)>().iter() {
+        if hole.filled_by.is_none()
+            && (hole_position.x, hole_position.y) == (position.x, position.y)
+        {
+            hole.filled_by = Some(entity);
+            events.push(Event::HoleFilled);
+        }
+    }
+}
+
+fn one_way_tiles(world: &World) -> HashMap<(u8, u8), Direction> {
+    world
+        .query::<(&Position, &OneWay)>()
+        .iter()
+        .map(|(_, (position, one_way))| ((position.x, position.y), one_way.direction))
+        .collect()
+}
+
+fn open_holes(world: &World) -> HashSet<(u8, u8)> {
+    world
+        .query::<(&Position, &Hole)>()
+        .iter()
+        .filter(|(_, (_, hole))| hole.filled_by.is_none())
+        .map(|(_, (position, _))| (position.x, position.y))
+        .collect()
+}
+
+// Whether anything can move onto `tile` in `direction` as far as
+// one-way tiles are concerned
+fn can_enter(one_way: &HashMap<(u8, u8), Direction>, tile: (u8, u8), direction: Direction) -> bool {
+    one_way
+        .get(&tile)
+        .is_none_or(|allowed| *allowed == direction)
+}
+
+// Opens the door if the players are holding a key for it, that is if they
+// collected more keys with its label than there are doors already opened.
+fn unlock_door(world: &World, door: Entity) -> bool {
+    let label = match world.get::<&Door>(door) {
+        Ok(door) if !door.open => door.label,
+        _ => return false,
+    };
+
+    let keys = world
+        .query::<&Key>()
+        .iter()
+        .filter(|(_, key)| key.label == label && key.collected)
+        .count();
+    let doors_opened = world
+        .query::<&Door>()
+        .iter()
+        .filter(|(_, door)| door.label == label && door.open)
+        .count();
+    if keys <= doors_opened {
+        return false;
+    }
+
+    world.get::<&mut Door>(door).unwrap().open = true;
+    true
+}
+
+// Moves an entity standing on a teleporter over to its partner,
+// unless something is in the way there.
+fn teleport(
+    world: &World,
+    resources: &Resources,
+    entity: Entity,
+    events: &mut Vec<Event>,
+) -> Result<(), ResourceError> {
+    let teleport_boxes = resources.get::<Config>()?.rules.teleport_boxes;
+    if world.get::<&Box>(entity).is_ok() && !teleport_boxes {
+        return Ok(());
+    }
+
+    let position = *world.get::<&Position>(entity).unwrap();
+    let exit = world
+        .query::<(&Position, &Teleporter)>()
+        .iter()
+        .find(|(_, (teleporter_position, _))| {
+            (teleporter_position.x, teleporter_position.y) == (position.x, position.y)
+        })
+        .and_then(|(_, (_, teleporter))| world.get::<&Position>(teleporter.pair).ok().map(|p| *p));
+    let exit = match exit {
+        Some(exit) => exit,
+        None => return Ok(()),
+    };
+
+    let exit_taken = world
+        .query::<(&Position, &Movable)>()
+        .iter()
+        .map(|(_, (position, _))| position)
+        .chain(
+            world
+                .query::<(&Position, &Immovable)>()
+                .iter()
+                .map(|(_, (position, _))| position),
+        )
+        .any(|taken| (taken.x, taken.y) == (exit.x, exit.y));
+    if exit_taken {
+        return Ok(());
+    }
+
+    let mut position = world.get::<&mut Position>(entity).unwrap();
+    position.x = exit.x;
+    position.y = exit.y;
+
+    events.push(Event::EntityTeleported(EntityTeleported { entity }));
+    Ok(())
+}
+
+// Keeps moving an entity that stands on ice in `direction`, until it reaches
+// a tile that isn't ice or something is in the way.
+fn slide(world: &World, entity: Entity, direction: Direction, events: &mut Vec<Event>) {
+    let ice: HashSet<(u8, u8)> = world
+        .query::<(&Position, &Ice)>()
+        .iter()
+        .map(|(_, (position, _))| (position.x, position.y))
+        .collect();
+    let occupied: HashSet<(u8, u8)> = world
+        .query::<(&Position, &Movable)>()
+        .iter()
+        .map(|(_, (position, _))| (position.x, position.y))
+        .chain(
+            world
+                .query::<(&Position, &Immovable)>()
+                .iter()
+                .map(|(_, (position, _))| (position.x, position.y)),
+        )
+        .collect();
+    let one_way = one_way_tiles(world);
+    // Boxes slide into holes, players stop at their edge
+    let holes = if world.get::<&Box>(entity).is_ok() {
+        HashSet::new()
+    } else {
+        open_holes(world)
+    };
+
+    let mut position = world.get::<&mut Position>(entity).unwrap();
+    let mut slid = false;
+    let mut hit_obstacle = false;
+
+    while ice.contains(&(position.x, position.y)) {
+        match direction.step((position.x, position.y)) {
+            Some(next)
+                if !occupied.contains(&next)
+                    && !holes.contains(&next)
+                    && can_enter(&one_way, next, direction) =>
+            {
+                position.x = next.0;
+                position.y = next.1;
+                slid = true;
+            }
+            _ => {
+                hit_obstacle = true;
+                break;
+            }
+        }
+    }
+
+    if slid {
+        events.push(Event::EntitySlid(EntitySlid {
+            entity,
+            hit_obstacle,
+        }));
+    }
+}
+
+// Works out what moving the player one tile in `direction` would move,
+// starting with the player itself followed by anything it pushes or pulls.
+// If an immovable or another player is in the way, or the rules don't
+// allow the push, nothing moves at all.
+fn collect_moves(
+    world: &World,
+    resources: &Resources,
+    player: Entity,
+    direction: Direction,
+    pull: bool,
+    events: &mut Vec<Event>,
+) -> Result<Vec<Entity>, ResourceError> {
+    let mut to_move: Vec<Entity> = Vec::new();
+
+    let rules = resources.get::<Config>()?.rules.clone();
+    let pull_mode = resources.get::<LevelRules>()?.pull_mode;
+
     // get all the movables and immovables
     let mov: HashMap<(u8, u8), Entity> = world
         .query::<(&Position, &Movable)>()
@@ -22,96 +413,693 @@ pub fn run_input(world: &World, context: &mut Context) {
         .iter()
         .map(|t| ((t.1 .0.x, t.1 .0.y), t.0))
         .collect::<HashMap<_, _>>();
+    let one_way = one_way_tiles(world);
+    let holes = open_holes(world);
 
-    for (_, (position, _player)) in world.query::<(&mut Position, &Player)>().iter() {
-        if context.keyboard.is_key_repeated() {
-            continue;
-        }
-
-        // Now iterate through current position to the end of the map
-        // on the correct axis and check what needs to move.
-        let key = if context.keyboard.is_key_just_pressed(KeyCode::Up) {
-            KeyCode::Up
-        } else if context.keyboard.is_key_just_pressed(KeyCode::Down) {
-            KeyCode::Down
-        } else if context.keyboard.is_key_just_pressed(KeyCode::Left) {
-            KeyCode::Left
-        } else if context.keyboard.is_key_just_pressed(KeyCode::Right) {
-            KeyCode::Right
-        } else {
-            continue;
-        };
+    let position = *world.get::<&Position>(player).unwrap();
+    let start = (position.x, position.y);
 
-        let (start, end, is_x) = match key {
-            KeyCode::Up => (position.y, 0, false),
-            KeyCode::Down => (position.y, MAP_HEIGHT - 1, false),
-            KeyCode::Left => (position.x, 0, true),
-            KeyCode::Right => (position.x, MAP_WIDTH - 1, true),
-            _ => continue,
-        };
+    // Now iterate through current position to the end of the map
+    // on the correct axis and check what needs to move.
+    let mut pos = Some(start);
 
-        let range = if start < end {
-            (start..=end).collect::<Vec<_>>()
-        } else {
-            (end..=start).rev().collect::<Vec<_>>()
+    while let Some(current) = pos {
+        // Nothing can move onto a one-way tile against its direction
+        if current != start && !can_enter(&one_way, current, direction) {
+            events.push(Event::PlayerHitObstacle {});
+            return Ok(Vec::new());
+        }
+
+        // find a movable
+        // if it exists, we try to move it and continue
+        // if it doesn't exist, we continue and try to find an immovable instead
+        match mov.get(&current) {
+            // Other players can't be pushed around, they are in the way just
+            // like an immovable is
+            Some(entity) if *entity != player && world.get::<&Player>(*entity).is_ok() => {
+                events.push(Event::PlayerHitObstacle {});
+                return Ok(Vec::new());
+            }
+            Some(entity) => to_move.push(*entity),
+            // A box can be pushed into a hole, but the player has to stop
+            // at its edge
+            None if holes.contains(&current) => {
+                if to_move.len() == 1 {
+                    events.push(Event::PlayerHitObstacle {});
+                    return Ok(Vec::new());
+                }
+                break;
+            }
+            None => {
+                // find an immovable
+                // if it exists, we need to stop and not move anything
+                // if it doesn't exist, we stop because we found a gap
+                match immov.get(&current) {
+                    // Walking straight into a locked door with its key opens it
+                    Some(id) if to_move.len() == 1 && unlock_door(world, *id) => {
+                        events.push(Event::DoorUnlocked);
+                        return Ok(Vec::new());
+                    }
+                    Some(_id) => {
+                        events.push(Event::PlayerHitObstacle {});
+                        return Ok(Vec::new());
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        pos = direction.step(current);
+    }
+
+    // The player is first in line, everything after it gets pushed,
+    // unless boxes can only be pulled
+    if to_move.len() > 1 && pull_mode || to_move.len() - 1 > rules.max_push_chain {
+        events.push(Event::PlayerHitObstacle {});
+        return Ok(Vec::new());
+    }
+
+    // Moving away from a box while pulling drags it along behind the player
+    let pulling = pull && rules.allow_pull || pull_mode;
+    if pulling && to_move.len() == 1 && can_enter(&one_way, start, direction) {
+        let behind = direction.opposite().step(start);
+        if let Some(entity) = behind.and_then(|behind| mov.get(&behind)) {
+            if world.get::<&Box>(*entity).is_ok() {
+                to_move.push(*entity);
+            }
+        }
+    }
+
+    Ok(to_move)
+}
+
+// Works out which direction (if any) should move the player this frame.
+// A direction that was just pressed moves straight away, one that is held
+// down starts repeating after the configured delay. The returned flag tells
+// whether this is a repeated move.
+fn next_direction(
+    world: &World,
+    resources: &Resources,
+    context: &Context,
+    player: Entity,
+    keys: &[KeyCode; 4],
+    gamepad_directions: &[Direction],
+) -> Result<Option<(Direction, bool)>, ResourceError> {
+    let config = &resources.get::<Config>()?.input;
+
+    let mut key_repeat = world.get::<&mut KeyRepeat>(player).unwrap();
+
+    let gamepad = resources.get::<GamepadInput>()?;
+    let uses_gamepad = world.get::<&Player>(player).unwrap().number == 1;
+
+    let just_pressed = Direction::ALL
+        .iter()
+        .zip(keys.iter())
+        .find(|(_, key)| context.keyboard.is_key_just_pressed(**key))
+        .map(|(direction, _)| *direction)
+        .or_else(|| gamepad_directions.first().copied());
+
+    if let Some(direction) = just_pressed {
+        *key_repeat = KeyRepeat {
+            direction: Some(direction),
+            next_repeat_at: Duration::from_millis(config.repeat_delay_ms),
+            ..Default::default()
         };
+        return Ok(Some((direction, false)));
+    }
+
+    let direction = match key_repeat.direction {
+        Some(direction) => direction,
+        None => return Ok(None),
+    };
+    let key_held = Direction::ALL
+        .iter()
+        .zip(keys.iter())
+        .any(|(held, key)| *held == direction && context.keyboard.is_key_pressed(*key));
+    let gamepad_held = uses_gamepad && gamepad.is_held(direction);
+    if !key_held && !gamepad_held {
+        *key_repeat = KeyRepeat::default();
+        return Ok(None);
+    }
+
+    key_repeat.held_for += context.time.delta();
+    if key_repeat.stopped || key_repeat.held_for < key_repeat.next_repeat_at {
+        return Ok(None);
+    }
+
+    key_repeat.next_repeat_at =
+        key_repeat.held_for + Duration::from_millis(config.repeat_interval_ms);
+    Ok(Some((direction, true)))
+}
+
+// Feeds a gamepad event into the gamepad input state, which `run_input`
+// then turns into moves and actions. Events coming from ggez go through
+// here, and so can synthetic ones.
+pub fn handle_gamepad_event(
+    resources: &Resources,
+    event: GamepadEvent,
+) -> Result<(), ResourceError> {
+    let deadzone = resources.get::<Config>()?.input.gamepad_deadzone;
+
+    let mut gamepad = resources.get_mut::<GamepadInput>()?;
 
-        for x_or_y in range {
-            let pos = if is_x {
-                (x_or_y, position.y)
+    match event {
+        GamepadEvent::ButtonDown(button) => {
+            if let Some(direction) = button_direction(button) {
+                gamepad.dpad.insert(direction);
+                gamepad.just_pressed.push(direction);
             } else {
-                (position.x, x_or_y)
-            };
+                gamepad.actions.extend(button_action(button));
+                gamepad.buttons.push(button);
+            }
+        }
+        GamepadEvent::ButtonUp(button) => {
+            if let Some(direction) = button_direction(button) {
+                gamepad.dpad.remove(&direction);
+            }
+        }
+        GamepadEvent::Axis(axis, value) => {
+            match axis {
+                Axis::LeftStickX => gamepad.stick.0 = value,
+                Axis::LeftStickY => gamepad.stick.1 = value,
+                _ => return Ok(()),
+            }
 
-            // find a movable
-            // if it exists, we try to move it and continue
-            // if it doesn't exist, we continue and try to find an immovable instead
-            match mov.get(&pos) {
-                Some(entity) => to_move.push((*entity, key)),
-                None => {
-                    // find an immovable
-                    // if it exists, we need to stop and not move anything
-                    // if it doesn't exist, we stop because we found a gap
-                    match immov.get(&pos) {
-                        Some(_id) => {
-                            to_move.clear();
-                            events.push(Event::PlayerHitObstacle {});
-                            break;
-                        }
-                        None => break,
-                    }
-                }
+            // Pushing the stick into a new direction counts as a press,
+            // keeping it there counts as holding it down.
+            let direction = stick_direction(gamepad.stick, deadzone);
+            if direction != gamepad.stick_direction {
+                gamepad.stick_direction = direction;
+                gamepad.just_pressed.extend(direction);
             }
         }
     }
 
-    // Update gameplay moves
-    if !to_move.is_empty() {
-        let mut query = world.query::<&mut Gameplay>();
-        let gameplay = query.iter().next().unwrap().1;
-        gameplay.moves_count += 1;
+    Ok(())
+}
+
+fn button_direction(button: Button) -> Option<Direction> {
+    match button {
+        Button::DPadUp => Some(Direction::Up),
+        Button::DPadDown => Some(Direction::Down),
+        Button::DPadLeft => Some(Direction::Left),
+        Button::DPadRight => Some(Direction::Right),
+        _ => None,
     }
+}
 
-    // Now actually move what needs to be moved
-    for (entity, key) in to_move {
-        let mut position = world.get::<&mut Position>(entity).unwrap();
+fn button_action(button: Button) -> Option<Action> {
+    match button {
+        Button::East | Button::West => Some(Action::Undo),
+        Button::Start => Some(Action::Restart),
+        _ => None,
+    }
+}
+
+fn stick_direction((x, y): (f32, f32), deadzone: f32) -> Option<Direction> {
+    if x.abs() < deadzone && y.abs() < deadzone {
+        return None;
+    }
+
+    // Go with whichever axis the stick is pushed along the most,
+    // up is positive on the y axis.
+    if x.abs() > y.abs() {
+        Some(if x > 0.0 {
+            Direction::Right
+        } else {
+            Direction::Left
+        })
+    } else {
+        Some(if y > 0.0 {
+            Direction::Up
+        } else {
+            Direction::Down
+        })
+    }
+}
+
+fn save_snapshot(world: &World, resources: &Resources) -> Result<(), ResourceError> {
+    let positions = world
+        .query::<(&Position, &Movable)>()
+        .iter()
+        .map(|(entity, (position, _))| (entity, *position))
+        .collect();
+
+    let keys_collected = world
+        .query::<&Key>()
+        .iter()
+        .map(|(entity, key)| (entity, key.collected))
+        .collect();
+    let doors_open = world
+        .query::<&Door>()
+        .iter()
+        .map(|(entity, door)| (entity, door.open))
+        .collect();
+    let holes_filled = world
+        .query::<&Hole>()
+        .iter()
+        .map(|(entity, hole)| (entity, hole.filled_by))
+        .collect();
+
+    let (moves_count, pushes_count) = {
+        let gameplay = resources.get::<Gameplay>()?;
+        (gameplay.moves_count, gameplay.pushes_count)
+    };
 
-        match key {
-            KeyCode::Up => position.y -= 1,
-            KeyCode::Down => position.y += 1,
-            KeyCode::Left => position.x -= 1,
-            KeyCode::Right => position.x += 1,
-            _ => (),
+    let mut history = resources.get_mut::<History>()?;
+    history.snapshots.push(Snapshot {
+        positions,
+        keys_collected,
+        doors_open,
+        holes_filled,
+        moves_count,
+        pushes_count,
+    });
+    Ok(())
+}
+
+fn restore_snapshot(
+    world: &World,
+    resources: &Resources,
+    snapshot: Snapshot,
+) -> Result<(), ResourceError> {
+    for (entity, position) in snapshot.positions {
+        if let Ok(mut current) = world.get::<&mut Position>(entity) {
+            *current = position;
+        }
+    }
+    for (entity, collected) in snapshot.keys_collected {
+        if let Ok(mut key) = world.get::<&mut Key>(entity) {
+            key.collected = collected;
+        }
+    }
+    for (entity, open) in snapshot.doors_open {
+        if let Ok(mut door) = world.get::<&mut Door>(entity) {
+            door.open = open;
+        }
+    }
+    for (entity, filled_by) in snapshot.holes_filled {
+        if let Ok(mut hole) = world.get::<&mut Hole>(entity) {
+            hole.filled_by = filled_by;
         }
+    }
 
-        // Fire an event for the entity that just moved
-        events.push(Event::EntityMoved(EntityMoved { entity }));
+    let mut gameplay = resources.get_mut::<Gameplay>()?;
+    gameplay.moves_count = snapshot.moves_count;
+    gameplay.pushes_count = snapshot.pushes_count;
+    Ok(())
+}
+
+// Returns whether there was anything to undo
+pub fn undo(world: &World, resources: &Resources) -> Result<bool, ResourceError> {
+    clear_player_path(resources)?;
+
+    let snapshot = resources.get_mut::<History>()?.snapshots.pop();
+
+    match snapshot {
+        Some(snapshot) => {
+            restore_snapshot(world, resources, snapshot)?;
+            Ok(true)
+        }
+        None => Ok(false),
     }
+}
 
-    // Finally add events back into the world
-    {
-        let mut query = world.query::<&mut EventQueue>();
-        let event_queue = query.iter().next().unwrap().1;
-        event_queue.events.append(&mut events);
+// Returns whether anything was moved since the level started
+fn restart(world: &World, resources: &Resources) -> Result<bool, ResourceError> {
+    clear_player_path(resources)?;
+
+    // The very first snapshot is how the level started out
+    let snapshot = {
+        let mut history = resources.get_mut::<History>()?;
+        std::mem::take(&mut history.snapshots).into_iter().next()
+    };
+
+    match snapshot {
+        Some(snapshot) => {
+            restore_snapshot(world, resources, snapshot)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+// Clicking a box selects it, clicking anywhere else walks the player
+// there, or pushes the selected box there if one was selected.
+fn handle_click(
+    world: &World,
+    resources: &Resources,
+    context: &Context,
+) -> Result<(), ResourceError> {
+    let mouse_position = context.mouse.position();
+    let x = (mouse_position.x / TILE_WIDTH) as i32;
+    let y = (mouse_position.y / TILE_WIDTH) as i32;
+    if x < 0 || y < 0 || x >= MAP_WIDTH as i32 || y >= MAP_HEIGHT as i32 {
+        return Ok(());
+    }
+    let target = (x as u8, y as u8);
+
+    let player = {
+        let mut query = world.query::<(&Position, &Player)>();
+        match query.iter().find(|(_, (_, player))| player.number == 1) {
+            Some((_, (position, _))) => (position.x, position.y),
+            None => return Ok(()),
+        }
+    };
+
+    // Boxes that went into a hole are gone, even though they're still there
+    let boxes: HashMap<(u8, u8), Entity> = world
+        .query::<(&Position, &Box)>()
+        .without::<&Hidden>()
+        .iter()
+        .map(|(entity, (position, _))| ((position.x, position.y), entity))
+        .collect();
+    // Walls, open holes and the other players are in the way. Pushing a
+    // box into a hole uses it up, so that's never part of a path either.
+    let immovables: HashSet<(u8, u8)> = world
+        .query::<(&Position, &Immovable)>()
+        .iter()
+        .map(|(_, (position, _))| (position.x, position.y))
+        .chain(open_holes(world))
+        .chain(
+            world
+                .query::<(&Position, &Player)>()
+                .iter()
+                .map(|(_, (position, _))| (position.x, position.y))
+                .filter(|position| *position != player),
+        )
+        .collect();
+
+    let ice: HashSet<(u8, u8)> = world
+        .query::<(&Position, &Ice)>()
+        .iter()
+        .map(|(_, (position, _))| (position.x, position.y))
+        .collect();
+
+    let teleporters: HashMap<(u8, u8), (u8, u8)> = world
+        .query::<(&Position, &Teleporter)>()
+        .iter()
+        .filter_map(|(_, (position, teleporter))| {
+            let exit = world.get::<&Position>(teleporter.pair).ok()?;
+            Some(((position.x, position.y), (exit.x, exit.y)))
+        })
+        .collect();
+
+    let one_way = one_way_tiles(world);
+    let pull_mode = resources.get::<LevelRules>()?.pull_mode;
+
+    let mut player_path = resources.get_mut::<PlayerPath>()?;
+
+    if let Some(clicked_box) = boxes.get(&target) {
+        player_path.steps.clear();
+        player_path.selected_box = Some(*clicked_box);
+        return Ok(());
+    }
+
+    let path = match player_path.selected_box.take() {
+        Some(selected_box) => {
+            let box_position = match world.get::<&Position>(selected_box) {
+                Ok(position) => (position.x, position.y),
+                Err(_) => return Ok(()),
+            };
+
+            // The box we are pushing is the only one allowed to move
+            let others: HashSet<(u8, u8)> = boxes
+                .keys()
+                .filter(|position| **position != box_position)
+                .copied()
+                .collect();
+            let grid = Grid {
+                blocked: others.iter().chain(immovables.iter()).copied().collect(),
+                ice,
+                teleporters,
+                one_way,
+                dragged: if pull_mode { others } else { HashSet::new() },
+            };
+            let rules = &resources.get::<Config>()?.rules;
+            find_push_path(player, box_position, target, &grid, rules, pull_mode)
+        }
+        None => {
+            // In pull mode walking away from a box drags it along, so the
+            // walk has to keep from doing that
+            let grid = Grid {
+                blocked: boxes.keys().chain(immovables.iter()).copied().collect(),
+                ice,
+                teleporters,
+                one_way,
+                dragged: if pull_mode {
+                    boxes.keys().copied().collect()
+                } else {
+                    HashSet::new()
+                },
+            };
+            find_walk_path(player, target, &grid)
+        }
+    };
+
+    if let Some(path) = path {
+        player_path.steps = path.into();
+        player_path.until_next_step = Duration::ZERO;
+    }
+
+    Ok(())
+}
+
+// The move a player makes this frame, given the direction (and whether it
+// repeats) from the keys or gamepad, if any. Direct input always takes over
+// from a walk that is still in progress, which only player 1 can click.
+fn next_move(
+    resources: &Resources,
+    number: u8,
+    direct: Option<(Direction, bool)>,
+    delta: Duration,
+) -> Result<Option<(Direction, bool)>, ResourceError> {
+    if number != 1 {
+        return Ok(direct);
+    }
+
+    if direct.is_some() {
+        clear_player_path(resources)?;
+        return Ok(direct);
+    }
+
+    Ok(next_path_step(resources, delta)?.map(|direction| (direction, false)))
+}
+
+// Takes the next step of a clicked path, once it's time to.
+fn next_path_step(
+    resources: &Resources,
+    delta: Duration,
+) -> Result<Option<Direction>, ResourceError> {
+    let step_interval =
+        Duration::from_millis(resources.get::<Config>()?.input.path_step_interval_ms);
+
+    let mut player_path = resources.get_mut::<PlayerPath>()?;
+
+    if player_path.steps.is_empty() {
+        return Ok(None);
+    }
+
+    player_path.until_next_step = player_path.until_next_step.saturating_sub(delta);
+    if !player_path.until_next_step.is_zero() {
+        return Ok(None);
+    }
+
+    player_path.until_next_step = step_interval;
+    Ok(player_path.steps.pop_front())
+}
+
+fn clear_player_path(resources: &Resources) -> Result<(), ResourceError> {
+    let mut player_path = resources.get_mut::<PlayerPath>()?;
+    player_path.steps.clear();
+    player_path.selected_box = None;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    fn resources() -> Resources {
+        let mut resources = Resources::default();
+        resources.insert(Config::default());
+        resources.insert(GamepadInput::default());
+        resources
+    }
+
+    fn send(resources: &Resources, events: Vec<GamepadEvent>) {
+        for event in events {
+            handle_gamepad_event(resources, event).unwrap();
+        }
+    }
+
+    #[test]
+    fn the_stick_goes_along_its_strongest_axis() {
+        assert_eq!(stick_direction((0.9, 0.2), 0.5), Some(Direction::Right));
+        assert_eq!(stick_direction((-0.9, 0.2), 0.5), Some(Direction::Left));
+        assert_eq!(stick_direction((0.3, 0.8), 0.5), Some(Direction::Up));
+        assert_eq!(stick_direction((0.3, -0.8), 0.5), Some(Direction::Down));
+    }
+
+    #[test]
+    fn the_stick_does_nothing_inside_the_deadzone() {
+        assert_eq!(stick_direction((0.0, 0.0), 0.5), None);
+        assert_eq!(stick_direction((0.4, -0.4), 0.5), None);
+        assert_eq!(stick_direction((0.6, 0.0), 0.7), None);
+    }
+
+    #[test]
+    fn the_dpad_is_pressed_and_held_until_released() {
+        let resources = resources();
+        send(&resources, vec![GamepadEvent::ButtonDown(Button::DPadLeft)]);
+        {
+            let gamepad = resources.get::<GamepadInput>().unwrap();
+            assert_eq!(gamepad.just_pressed, vec![Direction::Left]);
+            assert!(gamepad.is_held(Direction::Left));
+        }
+
+        send(&resources, vec![GamepadEvent::ButtonUp(Button::DPadLeft)]);
+        assert!(!resources
+            .get::<GamepadInput>()
+            .unwrap()
+            .is_held(Direction::Left));
+    }
+
+    #[test]
+    fn the_stick_counts_as_a_press_once_per_direction() {
+        let resources = resources();
+        send(
+            &resources,
+            vec![
+                GamepadEvent::Axis(Axis::LeftStickY, 0.8),
+                GamepadEvent::Axis(Axis::LeftStickY, 0.9),
+                GamepadEvent::Axis(Axis::LeftStickX, 1.0),
+                GamepadEvent::Axis(Axis::LeftStickY, 0.0),
+                GamepadEvent::Axis(Axis::RightStickX, 1.0),
+            ],
+        );
+
+        let gamepad = resources.get::<GamepadInput>().unwrap();
+        assert_eq!(gamepad.just_pressed, vec![Direction::Up, Direction::Right]);
+        assert!(gamepad.is_held(Direction::Right));
+        assert!(!gamepad.is_held(Direction::Up));
+    }
+
+    #[test]
+    fn the_stick_goes_by_the_configured_deadzone() {
+        let resources = resources();
+        resources
+            .get_mut::<Config>()
+            .unwrap()
+            .input
+            .gamepad_deadzone = 0.9;
+        send(&resources, vec![GamepadEvent::Axis(Axis::LeftStickX, 0.8)]);
+        assert!(resources
+            .get::<GamepadInput>()
+            .unwrap()
+            .just_pressed
+            .is_empty());
+    }
+
+    #[test]
+    fn buttons_are_kept_for_the_game_and_the_menus() {
+        let resources = resources();
+        send(
+            &resources,
+            vec![
+                GamepadEvent::ButtonDown(Button::East),
+                GamepadEvent::ButtonDown(Button::Start),
+                GamepadEvent::ButtonDown(Button::South),
+                GamepadEvent::ButtonUp(Button::South),
+            ],
+        );
+
+        let gamepad = resources.get::<GamepadInput>().unwrap();
+        assert_eq!(gamepad.actions, vec![Action::Undo, Action::Restart]);
+        assert_eq!(
+            gamepad.buttons,
+            vec![Button::East, Button::Start, Button::South]
+        );
+        assert!(gamepad.was_pressed(Button::South));
+        assert!(!gamepad.was_pressed(Button::North));
+    }
+
+    #[test]
+    fn menus_take_the_presses_from_the_game() {
+        let resources = resources();
+        send(
+            &resources,
+            vec![
+                GamepadEvent::ButtonDown(Button::DPadDown),
+                GamepadEvent::ButtonDown(Button::East),
+            ],
+        );
+
+        let mut gamepad = resources.get_mut::<GamepadInput>().unwrap();
+        let (directions, buttons) = gamepad.take_presses();
+        assert_eq!(directions, vec![Direction::Down]);
+        assert_eq!(buttons, vec![Button::East]);
+
+        // B goes back in a menu, it mustn't undo a move as well
+        assert!(gamepad.actions.is_empty());
+        assert!(gamepad.just_pressed.is_empty());
+        assert!(gamepad.is_held(Direction::Down));
+    }
+
+    #[test]
+    fn only_player_one_walks_the_clicked_path() {
+        let mut resources = resources();
+        resources.insert(PlayerPath {
+            steps: VecDeque::from([Direction::Right, Direction::Right, Direction::Down]),
+            ..PlayerPath::default()
+        });
+        let step = Duration::from_millis(Config::default().input.path_step_interval_ms);
+
+        // Both players take their turn every frame, player 2 mustn't eat
+        // into player 1's steps or their timing
+        let mut walked = Vec::new();
+        for _ in 0..3 {
+            walked.extend(next_move(&resources, 1, None, step).unwrap());
+            assert_eq!(next_move(&resources, 2, None, step).unwrap(), None);
+        }
+        assert_eq!(
+            walked,
+            vec![
+                (Direction::Right, false),
+                (Direction::Right, false),
+                (Direction::Down, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn keys_take_over_from_the_clicked_path() {
+        let mut resources = resources();
+        resources.insert(PlayerPath {
+            steps: VecDeque::from([Direction::Right, Direction::Right]),
+            ..PlayerPath::default()
+        });
+
+        // Player 2 pressing keys leaves player 1's walk alone
+        let direct = Some((Direction::Up, false));
+        assert_eq!(
+            next_move(&resources, 2, direct, Duration::ZERO).unwrap(),
+            direct
+        );
+        assert_eq!(resources.get::<PlayerPath>().unwrap().steps.len(), 2);
+
+        assert_eq!(
+            next_move(&resources, 1, direct, Duration::ZERO).unwrap(),
+            direct
+        );
+        assert!(resources.get::<PlayerPath>().unwrap().steps.is_empty());
+    }
+
+    #[test]
+    fn gamepad_events_need_the_config() {
+        let mut resources = Resources::default();
+        resources.insert(GamepadInput::default());
+        assert!(handle_gamepad_event(&resources, GamepadEvent::ButtonDown(Button::South)).is_err());
     }
 }
@@ -1,4 +1,101 @@
+use crate::schedule::{Schedule, Stage, System};
+
+pub mod achievements;
+pub mod audio;
+pub mod browser;
+pub mod daily;
 pub mod events;
 pub mod gameplay;
 pub mod input;
+pub mod profiler;
 pub mod rendering;
+pub mod stats;
+pub mod switches;
+pub mod time;
+
+// Every system of the game, and when it runs
+pub fn create_schedule() -> Schedule {
+    let mut schedule = Schedule::default();
+
+    // The level browser can swap out the level, and it and the stats
+    // screen take the keyboard from the game while they're open
+    schedule.add(System::new(
+        "browser",
+        Stage::Input,
+        |world, resources, context| browser::run_browser(world, resources, context),
+    ));
+    schedule.add(System::new(
+        "stats",
+        Stage::Input,
+        |_, resources, context| stats::run_stats(resources, context),
+    ));
+    schedule.add(
+        System::new("input", Stage::Input, |world, resources, context| {
+            input::run_input(world, resources, context)
+        })
+        .after("browser")
+        .after("stats"),
+    );
+    schedule.add(System::new(
+        "profiler",
+        Stage::Input,
+        |_, resources, context| {
+            profiler::run_profiler(resources, context);
+            Ok(())
+        },
+    ));
+
+    // Keys, doors and switches go before checking if the level is won
+    schedule.add(System::new(
+        "switches",
+        Stage::Logic,
+        |world, resources, _| switches::run_switches(world, resources),
+    ));
+    schedule.add(
+        System::new("gameplay", Stage::Logic, |world, resources, _| {
+            gameplay::run_gameplay_state(world, resources)
+        })
+        .after("switches"),
+    );
+    schedule.add(
+        System::new("daily", Stage::Logic, |_, resources, context| {
+            daily::run_daily(resources, context)
+        })
+        .after("gameplay"),
+    );
+
+    let event_bus = events::create_event_bus();
+    schedule.add(System::new(
+        "events",
+        Stage::PostLogic,
+        move |world, resources, context| {
+            events::run_process_events(world, resources, context, &event_bus)
+        },
+    ));
+    schedule.add(
+        System::new("time", Stage::PostLogic, |_, resources, context| {
+            time::run_time(resources, context)
+        })
+        .after("events"),
+    );
+    schedule.add(
+        System::new("achievements", Stage::PostLogic, |_, resources, context| {
+            achievements::run_achievements(resources, context)
+        })
+        .after("events"),
+    );
+
+    schedule.add(System::new(
+        "audio",
+        Stage::Audio,
+        |_, resources, context| audio::run_audio(resources, context),
+    ));
+
+    schedule.add(System::new(
+        "rendering",
+        Stage::Render,
+        |world, resources, context| rendering::run_rendering(world, resources, context),
+    ));
+
+    schedule
+}
@@ -0,0 +1,33 @@
+use ggez::{input::keyboard::KeyCode, Context};
+
+use crate::resources::Resources;
+use crate::schedule::Profiler;
+
+// F3 shows and hides how long each system takes. While that's showing,
+// Page Up and Page Down pick a system and F4 switches it on or off.
+pub fn run_profiler(resources: &Resources, context: &Context) {
+    if let Ok(mut profiler) = resources.get_mut::<Profiler>() {
+        let keyboard = &context.keyboard;
+        if keyboard.is_key_just_pressed(KeyCode::F3) {
+            profiler.open = !profiler.open;
+        }
+        if !profiler.open {
+            return;
+        }
+
+        if keyboard.is_key_just_pressed(KeyCode::PageUp) {
+            profiler.selected = profiler.selected.saturating_sub(1);
+        } else if keyboard.is_key_just_pressed(KeyCode::PageDown)
+            && profiler.selected + 1 < profiler.timings.len()
+        {
+            profiler.selected += 1;
+        } else if keyboard.is_key_just_pressed(KeyCode::F4) {
+            // Switching this one off would leave no way to switch it back on
+            profiler.toggle = profiler
+                .timings
+                .get(profiler.selected)
+                .map(|timing| timing.name)
+                .filter(|name| *name != "profiler");
+        }
+    }
+}
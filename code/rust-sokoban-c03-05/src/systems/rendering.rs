@@ -1,5 +1,7 @@
 use ggez::{
-    graphics::{self, Canvas, Color, DrawParam, Image, PxScale, Text, TextFragment},
+    graphics::{
+        self, Canvas, Color, DrawMode, DrawParam, Image, Mesh, PxScale, Rect, Text, TextFragment,
+    },
     Context,
 };
 use glam::Vec2;
@@ -11,23 +13,58 @@ use itertools::Itertools;
 use std::collections::HashMap;
 use std::time::Duration;
 
+use crate::achievements::{Achievements, TOAST_DURATION};
 use crate::components::*;
+use crate::config::Config;
 use crate::constants::*;
+use crate::daily::{format_day, Daily};
+use crate::levels::LevelBrowser;
+use crate::logging::RENDERING;
+use crate::resources::{ResourceError, Resources};
+use crate::schedule::Profiler;
+use crate::stats::{LevelStats, Stats};
+
+const TELEPORT_FADE_IN: Duration = Duration::from_millis(300);
 
 // ANCHOR: run_rendering
-pub fn run_rendering(world: &World, context: &mut Context) {
+pub fn run_rendering(
+    world: &World,
+    resources: &Resources,
+    context: &mut Context,
+) -> Result<(), ResourceError> {
     // Clearing the screen (this gives us the background colour)
     let mut canvas =
         graphics::Canvas::from_frame(context, graphics::Color::from([0.95, 0.95, 0.95, 1.0]));
     // ANCHOR_END: run_rendering
 
+    // The level browser and the stats screen cover the whole screen
+    // while they're open
+    {
+        if let Some(browser) = resources
+            .get::<LevelBrowser>()
+            .ok()
+            .filter(|browser| browser.open)
+        {
+            draw_level_browser(&mut canvas, &browser);
+            canvas.finish(context).expect("expected to present");
+            return Ok(());
+        }
+
+        if let Some(stats) = resources.get::<Stats>().ok().filter(|stats| stats.open) {
+            draw_stats(&mut canvas, &stats);
+            canvas.finish(context).expect("expected to present");
+            return Ok(());
+        }
+    }
+
     // Get time
-    let mut query = world.query::<&Time>();
-    let time = query.iter().next().unwrap().1;
+    let time = resources.get::<Time>()?;
 
     // Get all the renderables with their positions and sort by the position z
     // This will allow us to have entities layered visually.
-    let mut query = world.query::<(&Position, &Renderable)>();
+    let mut query = world
+        .query::<(&Position, &Renderable)>()
+        .without::<&Hidden>();
     let mut rendering_data: Vec<(Entity, (&Position, &Renderable))> = query.into_iter().collect();
     rendering_data.sort_by_key(|&k| k.1 .0.z);
 
@@ -36,15 +73,24 @@ pub fn run_rendering(world: &World, context: &mut Context) {
 
     // Iterate each of the renderables, determine which image path should be rendered
     // at which drawparams, and then add that to the rendering_batches.
-    for (_, (position, renderable)) in rendering_data.iter() {
-        // Load the image
+    for (entity, (position, renderable)) in rendering_data.iter() {
+        // Load the image, fallbacks were already picked when the level
+        // was loaded
         let image_path = get_image(renderable, time.delta);
+        let mut tint = renderable.tint();
+
+        // Fade in anything that just came out of a teleporter
+        if let Ok(teleported) = world.get::<&Teleported>(*entity) {
+            let since = time.delta.saturating_sub(teleported.at);
+            tint.a *= (since.as_secs_f32() / TELEPORT_FADE_IN.as_secs_f32()).min(1.0);
+        }
+
         let x = position.x as f32 * TILE_WIDTH;
         let y = position.y as f32 * TILE_WIDTH;
         let z = position.z;
 
         // draw
-        let draw_param = DrawParam::new().dest(Vec2::new(x, y));
+        let draw_param = DrawParam::new().dest(Vec2::new(x, y)).color(tint);
         rendering_batches
             .entry(z)
             .or_default()
@@ -54,6 +100,13 @@ pub fn run_rendering(world: &World, context: &mut Context) {
     }
     // ANCHOR_END: rendering_batches
 
+    log::trace!(
+        target: RENDERING,
+        "{} entities in {} layers",
+        rendering_data.len(),
+        rendering_batches.len()
+    );
+
     // ANCHOR: rendering_batches_2
     // Iterate spritebatches ordered by z and actually render each of them
     for (_z, group) in rendering_batches
@@ -74,24 +127,252 @@ pub fn run_rendering(world: &World, context: &mut Context) {
     // ANCHOR_END: rendering_batches_2
 
     // Render any text
-    let mut query = world.query::<&Gameplay>();
-    let gameplay = query.iter().next().unwrap().1;
+    let gameplay = resources.get::<Gameplay>()?;
     draw_text(&mut canvas, &gameplay.state.to_string(), 525.0, 80.0);
     draw_text(&mut canvas, &gameplay.moves_count.to_string(), 525.0, 100.0);
 
+    // Render the daily challenge, if that's what is being played
+    if let Ok(daily) = resources.get::<Daily>() {
+        draw_text(
+            &mut canvas,
+            &format!("Daily {}", format_day(daily.day)),
+            525.0,
+            160.0,
+        );
+        draw_text(
+            &mut canvas,
+            &format!(
+                "Streak: {} (best {})",
+                daily.current_streak(),
+                daily.record.best_streak
+            ),
+            525.0,
+            180.0,
+        );
+        if daily.solved_today() {
+            draw_text(&mut canvas, "Solved today!", 525.0, 200.0);
+        }
+
+        // The result to share goes under the level, wrapped to fit
+        if let Some(result) = &daily.result {
+            draw_text(&mut canvas, "Share your result:", 10.0, 310.0);
+            let mut text = Text::new(TextFragment {
+                text: result.clone(),
+                color: Some(Color::new(0.0, 0.0, 0.0, 1.0)),
+                scale: Some(PxScale::from(20.0)),
+                ..Default::default()
+            });
+            text.set_bounds(Vec2::new(490.0, f32::INFINITY));
+            canvas.draw(&text, Vec2::new(10.0, 334.0));
+        }
+    }
+
+    if resources.get::<Config>()?.audio.muted {
+        draw_text(&mut canvas, "Muted (M)", 525.0, 140.0);
+    }
+
+    if let Ok(browser) = resources.get::<LevelBrowser>() {
+        if !browser.index.levels.is_empty() {
+            draw_text(&mut canvas, "L (Y): levels", 525.0, 220.0);
+        }
+    }
+    draw_text(&mut canvas, "Tab (Select): stats", 525.0, 240.0);
+
     // ANCHOR: render_fps
     // Render FPS
     let fps = format!("FPS: {:.0}", context.time.fps());
     draw_text(&mut canvas, &fps, 525.0, 120.0);
     // ANCHOR_END: render_fps
 
+    if let Some(profiler) = resources
+        .get::<Profiler>()
+        .ok()
+        .filter(|profiler| profiler.open)
+    {
+        draw_profiler(&mut canvas, &profiler);
+    }
+
+    // Notices for achievements that were just unlocked go on top
+    if let Ok(achievements) = resources.get::<Achievements>() {
+        draw_toasts(&mut canvas, context, &achievements, time.delta);
+    }
+
     // ANCHOR: run_rendering_end
     // Finally, present the canvas, this will actually display everything
     // on the screen.
     canvas.finish(context).expect("expected to present");
+    Ok(())
 }
 // ANCHOR_END: run_rendering_end
 
+// How many levels fit on the screen at once
+const BROWSER_ROWS: usize = 20;
+
+fn draw_level_browser(canvas: &mut Canvas, browser: &LevelBrowser) {
+    let size_filter = browser
+        .size_filter
+        .map_or("any".to_string(), |size| size.to_string());
+    let author_filter = browser.author_filter.as_deref().unwrap_or("anyone");
+    draw_text(
+        canvas,
+        &format!(
+            "Sort by {} (1)   Show {} (2)   Size {} (3)   By {} (4)",
+            browser.sort_by, browser.solved_filter, size_filter, author_filter
+        ),
+        20.0,
+        20.0,
+    );
+
+    let columns = [40.0, 320.0, 470.0, 550.0, 620.0, 720.0];
+    let header = ["Title", "Author", "Size", "Boxes", "Rating", "Solved"];
+    for (x, text) in columns.iter().zip(header.iter()) {
+        draw_text(canvas, text, *x, 60.0);
+    }
+
+    let visible = browser.visible();
+    if visible.is_empty() {
+        draw_text(canvas, "No levels match", 40.0, 90.0);
+    }
+
+    // Scroll along so the selected level is always on screen
+    let first = (browser.selected + 1).saturating_sub(BROWSER_ROWS);
+    for (row, level) in visible.iter().enumerate().skip(first).take(BROWSER_ROWS) {
+        let y = 90.0 + (row - first) as f32 * 24.0;
+        if row == browser.selected {
+            draw_text(canvas, ">", 20.0, y);
+        }
+
+        let cells = [
+            level.title.clone(),
+            level.author.clone(),
+            format!("{}x{}", level.width, level.height),
+            level.boxes.to_string(),
+            level
+                .difficulty
+                .map_or("?".to_string(), |difficulty| difficulty.to_string()),
+            if browser.is_solved(level) { "yes" } else { "" }.to_string(),
+        ];
+        for (x, text) in columns.iter().zip(cells.iter()) {
+            draw_text(canvas, text, *x, y);
+        }
+    }
+
+    draw_text(
+        canvas,
+        "Up/Down to choose, Enter (A) to play, L (B) to go back",
+        20.0,
+        570.0,
+    );
+}
+
+// How many levels fit on the stats screen at once
+const STATS_ROWS: usize = 12;
+
+fn draw_stats(canvas: &mut Canvas, stats: &Stats) {
+    let columns = [20.0, 230.0, 350.0, 415.0, 485.0, 545.0, 620.0, 685.0, 740.0];
+    let header = [
+        "", "Solved", "Moves", "Pushes", "Undos", "Restarts", "Bumps", "Wrong", "Time",
+    ];
+    for (x, text) in columns.iter().zip(header.iter()) {
+        draw_text(canvas, text, *x, 20.0);
+    }
+
+    let row = |canvas: &mut Canvas, name: &str, level: &LevelStats, y: f32| {
+        let cells = [
+            name.to_string(),
+            format!(
+                "{}/{} ({:.0}%)",
+                level.completions,
+                level.attempts,
+                level.completion_rate() * 100.0
+            ),
+            level.moves.to_string(),
+            level.pushes.to_string(),
+            level.undos.to_string(),
+            level.restarts.to_string(),
+            level.wall_bumps.to_string(),
+            level.wrong_spots.to_string(),
+            format_duration(level.time_played()),
+        ];
+        for (x, text) in columns.iter().zip(cells.iter()) {
+            draw_text(canvas, text, *x, y);
+        }
+    };
+
+    row(canvas, "This session", &stats.session, 50.0);
+    row(canvas, "All time", &stats.all_time(), 74.0);
+
+    draw_text(canvas, "Levels", 20.0, 114.0);
+    let levels = stats.record.levels.values().skip(stats.scroll);
+    for (i, level) in levels.take(STATS_ROWS).enumerate() {
+        row(canvas, &level.title, level, 140.0 + i as f32 * 24.0);
+    }
+
+    draw_text(
+        canvas,
+        "Up/Down to scroll, E to export CSV, Tab (B) to go back",
+        20.0,
+        570.0,
+    );
+}
+
+// Like 1:05:09, or 5:09 under an hour
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+fn draw_profiler(canvas: &mut Canvas, profiler: &Profiler) {
+    draw_text(canvas, "PgUp/PgDn: pick, F4: on/off", 525.0, 280.0);
+    for (i, timing) in profiler.timings.iter().enumerate() {
+        let time = if timing.enabled {
+            format!("{:.2} ms", timing.average_time.as_secs_f32() * 1000.0)
+        } else {
+            "off".to_string()
+        };
+        let marker = if i == profiler.selected { ">" } else { " " };
+        draw_text(
+            canvas,
+            &format!("{} {:?} {}: {}", marker, timing.stage, timing.name, time),
+            525.0,
+            304.0 + i as f32 * 20.0,
+        );
+    }
+}
+
+fn draw_toasts(canvas: &mut Canvas, context: &Context, achievements: &Achievements, now: Duration) {
+    let toasts = achievements
+        .toasts
+        .iter()
+        .filter(|(_, at)| now.saturating_sub(*at) < TOAST_DURATION);
+
+    for (i, (achievement, _)) in toasts.enumerate() {
+        let y = 540.0 - i as f32 * 60.0;
+        let background = Mesh::new_rounded_rectangle(
+            context,
+            DrawMode::fill(),
+            Rect::new(10.0, y, 480.0, 50.0),
+            8.0,
+            Color::new(1.0, 0.9, 0.6, 0.9),
+        )
+        .unwrap();
+        canvas.draw(&background, DrawParam::new());
+
+        draw_text(
+            canvas,
+            &format!("Achievement unlocked: {}", achievement.title()),
+            20.0,
+            y + 4.0,
+        );
+        draw_text(canvas, achievement.description(), 20.0, y + 26.0);
+    }
+}
+
 pub fn draw_text(canvas: &mut Canvas, text_string: &str, x: f32, y: f32) {
     let text = Text::new(TextFragment {
         text: text_string.to_string(),
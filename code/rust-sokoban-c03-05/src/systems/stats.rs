@@ -0,0 +1,112 @@
+use ggez::{
+    input::{gamepad::gilrs::Button, keyboard::KeyCode},
+    Context,
+};
+use hecs::World;
+
+use crate::components::*;
+use crate::events::*;
+use crate::levels::LevelBrowser;
+use crate::resources::{ResourceError, Resources};
+use crate::stats::*;
+
+// Counts what happens in the levels being played
+pub fn track_stats<C>(
+    world: &mut World,
+    resources: &Resources,
+    _context: &mut C,
+    event: &Event,
+    _: &mut Vec<Event>,
+) -> Result<(), ResourceError> {
+    let is_player = match event {
+        Event::EntityMoved(EntityMoved { entity, .. }) => world.get::<&Player>(*entity).is_ok(),
+        _ => false,
+    };
+
+    // Stats are optional, e.g. when verifying levels
+    if !resources.contains::<Stats>() {
+        return Ok(());
+    }
+    let mut stats = resources.get_mut::<Stats>()?;
+
+    match event {
+        Event::LevelStarted(LevelStarted { fingerprint, title }) => {
+            stats.start_level(fingerprint, title)
+        }
+        Event::EntityMoved(_) if is_player => stats.count(|stats| stats.moves += 1),
+        Event::BoxPushed => stats.count(|stats| stats.pushes += 1),
+        Event::MoveUndone => stats.count(|stats| stats.undos += 1),
+        Event::LevelRestarted => stats.count(|stats| stats.restarts += 1),
+        Event::PlayerHitObstacle => stats.count(|stats| stats.wall_bumps += 1),
+        Event::BoxPlacedOnSpot(BoxPlacedOnSpot {
+            is_correct_spot: false,
+        }) => stats.count(|stats| stats.wrong_spots += 1),
+        Event::LevelWon => stats.complete(),
+        _ => (),
+    }
+    Ok(())
+}
+
+// Tab opens and closes the stats screen, where Up/Down scroll through the
+// levels and E exports them to a CSV file. On the gamepad Select opens it,
+// the D-pad scrolls and B goes back. Also keeps track of the time spent
+// playing, and saves the stats every so often.
+pub fn run_stats(resources: &Resources, context: &Context) -> Result<(), ResourceError> {
+    if !resources.contains::<Stats>() {
+        return Ok(());
+    }
+    let mut stats = resources.get_mut::<Stats>()?;
+
+    let in_browser = resources
+        .get::<LevelBrowser>()
+        .is_ok_and(|browser| browser.open);
+
+    let keyboard = &context.keyboard;
+    let mut toggled = false;
+    let toggle = keyboard.is_key_just_pressed(KeyCode::Tab)
+        || resources.get::<GamepadInput>()?.was_pressed(Button::Select);
+    if toggle && !in_browser {
+        stats.open = !stats.open;
+        stats.scroll = 0;
+        toggled = true;
+
+        let mut event_queue = resources.get_mut::<EventQueue>()?;
+        event_queue.events.push(Event::MenuNavigated);
+    }
+
+    if stats.open {
+        let (directions, buttons) = resources.get_mut::<GamepadInput>()?.take_presses();
+        if keyboard.is_key_just_pressed(KeyCode::Up) || directions.contains(&Direction::Up) {
+            stats.scroll = stats.scroll.saturating_sub(1);
+        } else if (keyboard.is_key_just_pressed(KeyCode::Down)
+            || directions.contains(&Direction::Down))
+            && stats.scroll + 1 < stats.record.levels.len()
+        {
+            stats.scroll += 1;
+        } else if buttons.contains(&Button::East) {
+            stats.open = false;
+            toggled = true;
+            resources
+                .get_mut::<EventQueue>()?
+                .events
+                .push(Event::MenuNavigated);
+        } else if keyboard.is_key_just_pressed(KeyCode::E) {
+            export_csv(context, &stats);
+        }
+    } else if !in_browser {
+        // Only time actually spent on a level counts
+        let won = matches!(resources.get::<Gameplay>()?.state, GameplayState::Won);
+        if !won {
+            let played = context.time.delta().as_millis() as u64;
+            stats.count(|stats| stats.time_played_ms += played);
+        }
+    }
+
+    let now = context.time.time_since_start();
+    if stats.changed && (toggled || now.saturating_sub(stats.saved_at) >= SAVE_INTERVAL) {
+        save_record(context, &stats.record);
+        stats.changed = false;
+        stats.saved_at = now;
+    }
+    Ok(())
+}
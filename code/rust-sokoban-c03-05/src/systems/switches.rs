@@ -0,0 +1,136 @@
+use crate::components::*;
+use crate::events::*;
+use crate::resources::{ResourceError, Resources};
+use hecs::{Entity, World};
+
+use std::collections::HashSet;
+
+pub fn run_switches(world: &mut World, resources: &Resources) -> Result<(), ResourceError> {
+    let mut events = Vec::new();
+
+    let players: HashSet<(u8, u8)> = world
+        .query::<(&Position, &Player)>()
+        .iter()
+        .map(|(_, (position, _))| (position.x, position.y))
+        .collect();
+    let occupied: HashSet<(u8, u8)> = world
+        .query::<(&Position, &Movable)>()
+        .iter()
+        .map(|(_, (position, _))| (position.x, position.y))
+        .collect();
+
+    // Players pick up any key they are standing on
+    for (_, (position, key)) in world.query_mut::<(&Position, &mut Key)>() {
+        if !key.collected && players.contains(&(position.x, position.y)) {
+            key.collected = true;
+            events.push(Event::KeyCollected);
+        }
+    }
+
+    // Gates are open while anything stands on one of their pressure plates,
+    // and stay open for as long as something is in the way of closing them
+    let pressed: HashSet<u8> = world
+        .query::<(&Position, &PressurePlate)>()
+        .iter()
+        .filter(|(_, (position, _))| occupied.contains(&(position.x, position.y)))
+        .map(|(_, (_, plate))| plate.label)
+        .collect();
+
+    for (_, (position, gate)) in world.query_mut::<(&Position, &mut Gate)>() {
+        let open = pressed.contains(&gate.label) || occupied.contains(&(position.x, position.y));
+        if open != gate.open {
+            gate.open = open;
+            events.push(if open {
+                Event::GateOpened
+            } else {
+                Event::GateClosed
+            });
+        }
+    }
+
+    // Finally bring the world in line with the state of keys, doors, gates
+    // and holes.
+    // Undo can bring back keys and close doors, so this goes both ways.
+    let keys: Vec<(Entity, bool)> = world
+        .query::<&Key>()
+        .iter()
+        .map(|(entity, key)| (entity, key.collected))
+        .collect();
+    for (entity, collected) in keys {
+        set_hidden(world, entity, collected);
+    }
+
+    let passages: Vec<(Entity, bool)> = world
+        .query::<&Door>()
+        .iter()
+        .map(|(entity, door)| (entity, door.open))
+        .chain(
+            world
+                .query::<&Gate>()
+                .iter()
+                .map(|(entity, gate)| (entity, gate.open)),
+        )
+        .collect();
+    for (entity, open) in passages {
+        if set_hidden(world, entity, open) {
+            if open {
+                world.remove_one::<Immovable>(entity).unwrap();
+            } else {
+                world.insert_one(entity, Immovable {}).unwrap();
+            }
+        }
+    }
+
+    // A filled hole shows the floor underneath, and the box that went
+    // into it is gone for good
+    let holes: Vec<(Entity, Option<Entity>)> = world
+        .query::<&Hole>()
+        .iter()
+        .map(|(entity, hole)| (entity, hole.filled_by))
+        .collect();
+    let consumed: HashSet<Entity> = holes
+        .iter()
+        .filter_map(|(_, filled_by)| *filled_by)
+        .collect();
+    for (entity, filled_by) in holes {
+        set_hidden(world, entity, filled_by.is_some());
+    }
+
+    let boxes: Vec<Entity> = world
+        .query::<&Box>()
+        .iter()
+        .map(|(entity, _)| entity)
+        .collect();
+    for entity in boxes {
+        let is_consumed = consumed.contains(&entity);
+        if set_hidden(world, entity, is_consumed) {
+            if is_consumed {
+                world.remove_one::<Movable>(entity).unwrap();
+            } else {
+                world.insert_one(entity, Movable {}).unwrap();
+            }
+        }
+    }
+
+    // Finally add events back into the world
+    {
+        let mut event_queue = resources.get_mut::<EventQueue>()?;
+        event_queue.events.append(&mut events);
+    }
+    Ok(())
+}
+
+// Shows or hides an entity, returns whether that changed anything
+fn set_hidden(world: &mut World, entity: Entity, hidden: bool) -> bool {
+    if world.get::<&Hidden>(entity).is_ok() == hidden {
+        return false;
+    }
+
+    if hidden {
+        world.insert_one(entity, Hidden {}).unwrap();
+    } else {
+        world.remove_one::<Hidden>(entity).unwrap();
+    }
+
+    true
+}
@@ -0,0 +1,10 @@
+use ggez::Context;
+
+use crate::components::*;
+use crate::resources::{ResourceError, Resources};
+
+pub fn run_time(resources: &Resources, context: &Context) -> Result<(), ResourceError> {
+    let mut time = resources.get_mut::<Time>()?;
+    time.delta += context.time.delta();
+    Ok(())
+}
@@ -0,0 +1,552 @@
+use crate::components::*;
+use crate::config::{Config, Rules};
+use crate::events::Event;
+use crate::map::load_map;
+use crate::resources::Resources;
+use crate::systems::gameplay::run_gameplay_state;
+use crate::systems::input::move_player;
+use crate::systems::switches::run_switches;
+use hecs::{Entity, World};
+
+use std::{fmt, fs};
+
+const USAGE: &str = "usage: rust-sokoban verify [--max-push-chain <n>] [--allow-pull] \
+[--no-teleport-boxes] <level file> <solution or solution file>";
+
+// What went wrong with a solution, at which step (counting from 1)
+pub struct IllegalStep {
+    pub step: usize,
+    pub letter: char,
+    pub reason: &'static str,
+}
+
+pub struct Verification {
+    pub solved: bool,
+    pub moves: u32,
+    pub pushes: u32,
+    pub illegal_step: Option<IllegalStep>,
+}
+
+impl Verification {
+    pub fn is_valid(&self) -> bool {
+        self.solved && self.illegal_step.is_none()
+    }
+}
+
+impl fmt::Display for Verification {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match &self.illegal_step {
+            Some(illegal) => writeln!(
+                fmt,
+                "Invalid: step {} ({}) {}",
+                illegal.step, illegal.letter, illegal.reason
+            )?,
+            None if !self.solved => writeln!(fmt, "Invalid: the level isn't solved at the end")?,
+            None => writeln!(fmt, "Valid")?,
+        }
+        write!(fmt, "{} moves, {} pushes", self.moves, self.pushes)
+    }
+}
+
+// Verifies the level and solution given on the command line of `verify`.
+// The rules start out as the default ones, flags in front of the level
+// file change them to those the solution was played under.
+pub fn verify_args(args: &[String]) -> Result<Verification, String> {
+    let mut rules = Rules::default();
+    let mut args = args.iter();
+    let (level_path, solution) = loop {
+        match args.next().map(String::as_str) {
+            Some("--max-push-chain") => {
+                rules.max_push_chain = args
+                    .next()
+                    .and_then(|value| value.parse().ok())
+                    .ok_or_else(|| format!("--max-push-chain takes a number\n{}", USAGE))?
+            }
+            Some("--allow-pull") => rules.allow_pull = true,
+            Some("--no-teleport-boxes") => rules.teleport_boxes = false,
+            Some(level_path) => match (args.next(), args.next()) {
+                (Some(solution), None) => break (level_path, solution),
+                _ => return Err(USAGE.to_string()),
+            },
+            None => return Err(USAGE.to_string()),
+        }
+    };
+
+    let level = fs::read_to_string(level_path)
+        .map_err(|error| format!("couldn't read {}: {}", level_path, error))?;
+    let solution = fs::read_to_string(solution).unwrap_or_else(|_| solution.clone());
+    verify(&level, &solution, rules)
+}
+
+// Replays a solution in LURD notation on a level, the way the game itself
+// would play it: lower case letters walk, upper case letters push (or pull,
+// in pull mode) a box. Stops at the first step that can't be played, and
+// fails if the level itself is broken.
+pub fn verify(map: &str, solution: &str, rules: Rules) -> Result<Verification, String> {
+    let (mut world, resources, player) = load(map, rules)?;
+
+    let letters = solution.chars().filter(|letter| !letter.is_whitespace());
+    for (i, letter) in letters.enumerate() {
+        // Counts are up to, not including, an illegal step
+        let (moves, pushes) = counts(&resources);
+        if let Err(reason) = play_step(&mut world, &resources, player, letter) {
+            return Ok(Verification {
+                solved: false,
+                moves,
+                pushes,
+                illegal_step: Some(IllegalStep {
+                    step: i + 1,
+                    letter,
+                    reason,
+                }),
+            });
+        }
+    }
+
+    let (moves, pushes) = counts(&resources);
+    let solved = matches!(
+        resources.get::<Gameplay>().unwrap().state,
+        GameplayState::Won
+    );
+    Ok(Verification {
+        solved,
+        moves,
+        pushes,
+        illegal_step: None,
+    })
+}
+
+// Sets up a level to play without ggez, with just what the game systems
+// need. Returns it along with player 1.
+fn load(map: &str, rules: Rules) -> Result<(World, Resources, Entity), String> {
+    let mut world = World::new();
+    let mut resources = Resources::default();
+    resources.insert(Config {
+        rules,
+        ..Config::default()
+    });
+    resources.insert(Gameplay::default());
+    resources.insert(LevelRules::default());
+    resources.insert(Time::default());
+    resources.insert(PlayerPath::default());
+    resources.insert(History::default());
+    resources.insert(EventQueue::default());
+    load_map(&mut world, &resources, map.to_string())?;
+
+    let player = world
+        .query::<&Player>()
+        .iter()
+        .find(|(_, player)| player.number == 1)
+        .map(|(entity, _)| entity)
+        .ok_or("the level has no player")?;
+    Ok((world, resources, player))
+}
+
+fn play_step(
+    world: &mut World,
+    resources: &Resources,
+    player: Entity,
+    letter: char,
+) -> Result<(), &'static str> {
+    let direction = match letter.to_ascii_lowercase() {
+        'u' => Direction::Up,
+        'd' => Direction::Down,
+        'l' => Direction::Left,
+        'r' => Direction::Right,
+        _ => return Err("is not one of l, u, r or d"),
+    };
+    let is_push = letter.is_ascii_uppercase();
+
+    let (moves_before, pushes_before) = counts(resources);
+    let mut events = Vec::new();
+    // Everything the verifier needs was inserted above
+    move_player(
+        world,
+        resources,
+        player,
+        direction,
+        false,
+        is_push,
+        &mut events,
+    )
+    .unwrap();
+    run_switches(world, resources).unwrap();
+    run_gameplay_state(world, resources).unwrap();
+
+    // Nobody is listening for the events, so don't let them pile up
+    resources.get_mut::<EventQueue>().unwrap().events.clear();
+
+    // Walking into a locked door with its key opens it without moving, the
+    // next step in the same direction goes through
+    let unlocked_door = events
+        .iter()
+        .any(|event| matches!(event, Event::DoorUnlocked));
+
+    let (moves, pushes) = counts(resources);
+    if unlocked_door && is_push {
+        return Err("doesn't move a box");
+    } else if unlocked_door {
+        return Ok(());
+    }
+    if moves == moves_before {
+        return Err("runs into something");
+    }
+    match (is_push, pushes > pushes_before) {
+        (true, false) => Err("doesn't move a box"),
+        (false, true) => Err("moves a box, which takes an upper case letter"),
+        _ => Ok(()),
+    }
+}
+
+fn counts(resources: &Resources) -> (u32, u32) {
+    let gameplay = resources.get::<Gameplay>().unwrap();
+    (gameplay.moves_count, gameplay.pushes_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systems::input::undo;
+
+    // One box, one step away from its spot
+    const LEVEL: &str = "
+    W W W W W W
+    W P . BB BS W
+    W W W W W W
+    ";
+
+    // Plays the steps on a level and returns it, to look at more closely
+    // than `verify` does
+    fn play(map: &str, steps: &str, rules: Rules) -> (World, Resources, Entity) {
+        let (mut world, resources, player) = load(map, rules).unwrap();
+        for letter in steps.chars() {
+            play_step(&mut world, &resources, player, letter).unwrap();
+        }
+        (world, resources, player)
+    }
+
+    fn position(world: &World, entity: Entity) -> (u8, u8) {
+        let position = world.get::<&Position>(entity).unwrap();
+        (position.x, position.y)
+    }
+
+    fn find_box(world: &World, tile: (u8, u8)) -> Option<Entity> {
+        world
+            .query::<(&Position, &Box)>()
+            .iter()
+            .find(|(_, (position, _))| (position.x, position.y) == tile)
+            .map(|(entity, _)| entity)
+    }
+
+    fn box_at(world: &World, tile: (u8, u8)) -> Entity {
+        find_box(world, tile).unwrap()
+    }
+
+    #[test]
+    fn accepts_a_valid_solution() {
+        let verification = verify(LEVEL, "rR", Rules::default()).unwrap();
+        assert!(verification.is_valid(), "{}", verification);
+        assert_eq!((verification.moves, verification.pushes), (2, 1));
+    }
+
+    #[test]
+    fn stops_at_an_illegal_step() {
+        let verification = verify(LEVEL, "rlluR", Rules::default()).unwrap();
+        let illegal = verification.illegal_step.unwrap();
+        assert_eq!((illegal.step, illegal.letter), (3, 'l'));
+        assert_eq!(illegal.reason, "runs into something");
+        // Up to, not including, the illegal step
+        assert_eq!((verification.moves, verification.pushes), (2, 0));
+    }
+
+    #[test]
+    fn rejects_unknown_letters() {
+        let verification = verify(LEVEL, "rx", Rules::default()).unwrap();
+        let illegal = verification.illegal_step.unwrap();
+        assert_eq!((illegal.step, illegal.letter), (2, 'x'));
+    }
+
+    #[test]
+    fn pushes_take_upper_case_letters() {
+        let verification = verify(LEVEL, "rr", Rules::default()).unwrap();
+        let illegal = verification.illegal_step.unwrap();
+        assert_eq!(illegal.step, 2);
+        assert_eq!(
+            illegal.reason,
+            "moves a box, which takes an upper case letter"
+        );
+
+        let verification = verify(LEVEL, "R", Rules::default()).unwrap();
+        let illegal = verification.illegal_step.unwrap();
+        assert_eq!(illegal.step, 1);
+        assert_eq!(illegal.reason, "doesn't move a box");
+    }
+
+    #[test]
+    fn has_to_end_on_a_won_level() {
+        let verification = verify(LEVEL, "r", Rules::default()).unwrap();
+        assert!(verification.illegal_step.is_none());
+        assert!(!verification.solved);
+        assert!(!verification.is_valid());
+    }
+
+    #[test]
+    fn boxes_only_go_on_spots_of_their_colour() {
+        let map = "
+        W W W W W
+        W P BB RS W
+        W W W W W
+        ";
+        let verification = verify(map, "R", Rules::default()).unwrap();
+        assert!(verification.illegal_step.is_none());
+        assert!(!verification.solved);
+    }
+
+    #[test]
+    fn plays_by_the_given_rules() {
+        let map = "
+        W W W W W W W
+        W P BB BB BS . W
+        W W W W W W W
+        ";
+        let verification = verify(map, "R", Rules::default()).unwrap();
+        assert!(verification.illegal_step.is_some());
+
+        let rules = Rules {
+            max_push_chain: 2,
+            ..Rules::default()
+        };
+        let verification = verify(map, "R", rules).unwrap();
+        assert!(verification.is_valid(), "{}", verification);
+    }
+
+    #[test]
+    fn fails_on_broken_levels() {
+        assert!(verify("W . W", "r", Rules::default()).is_err());
+        assert!(verify("mode: sideways\nW P W", "r", Rules::default()).is_err());
+        assert!(verify("W P ?? W", "r", Rules::default()).is_err());
+    }
+
+    #[test]
+    fn opens_doors_with_a_key() {
+        let map = "
+        W W W W W W W W
+        W P K1 D1 BB BS . W
+        W W W W W W W W
+        ";
+
+        let verification = verify(map, "rrrR", Rules::default()).unwrap();
+        assert!(verification.is_valid(), "{}", verification);
+        assert_eq!((verification.moves, verification.pushes), (3, 1));
+    }
+
+    // A box goes into the hole, the other one onto the spot
+    const HOLE_LEVEL: &str = "
+    W W W W W W
+    W P BB H . W
+    W . BB BS . W
+    W W W W W W
+    ";
+
+    #[test]
+    fn the_player_stops_at_a_hole() {
+        let map = "
+        W W W W W W
+        W P H BB BS W
+        W W W W W W
+        ";
+        let verification = verify(map, "r", Rules::default()).unwrap();
+        let illegal = verification.illegal_step.unwrap();
+        assert_eq!(illegal.step, 1);
+        assert_eq!(illegal.reason, "runs into something");
+    }
+
+    #[test]
+    fn boxes_in_holes_are_left_out_of_the_win() {
+        let verification = verify(HOLE_LEVEL, "RldR", Rules::default()).unwrap();
+        assert!(verification.is_valid(), "{}", verification);
+        assert_eq!((verification.moves, verification.pushes), (4, 2));
+    }
+
+    #[test]
+    fn boxes_fall_into_holes_for_good() {
+        let (world, _, player) = play(HOLE_LEVEL, "Rr", Rules::default());
+        let the_box = box_at(&world, (3, 1));
+        assert!(world.get::<&Hidden>(the_box).is_ok());
+        assert!(world.get::<&Movable>(the_box).is_err());
+
+        // The filled hole shows the floor, which can be walked on
+        let (hole, _) = world.query::<&Hole>().iter().next().unwrap();
+        assert_eq!(world.get::<&Hole>(hole).unwrap().filled_by, Some(the_box));
+        assert!(world.get::<&Hidden>(hole).is_ok());
+        assert_eq!(position(&world, player), (3, 1));
+    }
+
+    #[test]
+    fn undo_brings_a_box_back_out_of_a_hole() {
+        let (mut world, resources, _) = play(HOLE_LEVEL, "R", Rules::default());
+        let the_box = box_at(&world, (3, 1));
+
+        assert!(undo(&world, &resources).unwrap());
+        run_switches(&mut world, &resources).unwrap();
+
+        assert_eq!(position(&world, the_box), (2, 1));
+        assert!(world.get::<&Hidden>(the_box).is_err());
+        assert!(world.get::<&Movable>(the_box).is_ok());
+        let (hole, _) = world.query::<&Hole>().iter().next().unwrap();
+        assert_eq!(world.get::<&Hole>(hole).unwrap().filled_by, None);
+        assert!(world.get::<&Hidden>(hole).is_err());
+    }
+
+    // Moves the player and returns the events that fired
+    fn step(
+        world: &World,
+        resources: &Resources,
+        player: Entity,
+        direction: Direction,
+    ) -> Vec<Event> {
+        let mut events = Vec::new();
+        move_player(
+            world,
+            resources,
+            player,
+            direction,
+            false,
+            false,
+            &mut events,
+        )
+        .unwrap();
+        events
+    }
+
+    fn slid(events: &[Event], entity: Entity) -> Option<bool> {
+        events.iter().find_map(|event| match event {
+            Event::EntitySlid(slid) if slid.entity == entity => Some(slid.hit_obstacle),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn the_player_slides_over_ice_until_something_is_in_the_way() {
+        let map = "
+        W W W W W W W
+        W P I I W . W
+        W P2 I I . BB W
+        W W W W W W W
+        ";
+        let (world, resources, player) = load(map, Rules::default()).unwrap();
+        let events = step(&world, &resources, player, Direction::Right);
+        assert_eq!(position(&world, player), (3, 1));
+        assert_eq!(slid(&events, player), Some(true));
+
+        // Ice ending in floor just stops the slide
+        let (player_2, _) = world
+            .query::<&Player>()
+            .iter()
+            .find(|(_, player)| player.number == 2)
+            .unwrap();
+        let events = step(&world, &resources, player_2, Direction::Right);
+        assert_eq!(position(&world, player_2), (4, 2));
+        assert_eq!(slid(&events, player_2), Some(false));
+    }
+
+    #[test]
+    fn pushed_boxes_slide_up_to_the_next_box() {
+        let map = "
+        W W W W W W W W
+        W P BB I I BB . W
+        W W W W W W W W
+        ";
+        let (world, resources, player) = load(map, Rules::default()).unwrap();
+        let the_box = box_at(&world, (2, 1));
+        let events = step(&world, &resources, player, Direction::Right);
+        assert_eq!(position(&world, the_box), (4, 1));
+        assert_eq!(slid(&events, the_box), Some(true));
+        // The player isn't on ice, so it stays right where it stepped
+        assert_eq!(position(&world, player), (2, 1));
+        assert_eq!(slid(&events, player), None);
+    }
+
+    #[test]
+    fn boxes_can_slide_onto_their_spot() {
+        let map = "
+        W W W W W W W
+        W P BB I I BS W
+        W W W W W W W
+        ";
+        let verification = verify(map, "R", Rules::default()).unwrap();
+        assert!(verification.is_valid(), "{}", verification);
+    }
+
+    // The box in front of the player goes through to the lower room
+    const TELEPORTER_LEVEL: &str = "
+    W W W W W W W
+    W P BB T1 . . W
+    W W W W W W W
+    W . . T1 . . W
+    W W W W W W W
+    ";
+
+    #[test]
+    fn the_player_goes_through_teleporters() {
+        let map = "
+        W W W W W W
+        W P T1 . . W
+        W W W W W W
+        W . T1 . . W
+        W W W W W W
+        ";
+        let (world, resources, player) = load(map, Rules::default()).unwrap();
+        let events = step(&world, &resources, player, Direction::Right);
+        assert_eq!(position(&world, player), (2, 3));
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Event::EntityTeleported(teleported) if teleported.entity == player)));
+    }
+
+    #[test]
+    fn boxes_go_through_teleporters_by_the_rules() {
+        let (world, _, player) = play(TELEPORTER_LEVEL, "R", Rules::default());
+        assert!(find_box(&world, (3, 3)).is_some());
+        assert_eq!(position(&world, player), (2, 1));
+
+        let rules = Rules {
+            teleport_boxes: false,
+            ..Rules::default()
+        };
+        let (world, _, _) = play(TELEPORTER_LEVEL, "R", rules);
+        assert!(find_box(&world, (3, 1)).is_some());
+    }
+
+    #[test]
+    fn teleporters_with_a_blocked_exit_do_nothing() {
+        // The box that went through sits on the other teleporter
+        let (world, resources, player) = play(TELEPORTER_LEVEL, "R", Rules::default());
+        let events = step(&world, &resources, player, Direction::Right);
+        assert_eq!(position(&world, player), (3, 1));
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, Event::EntityTeleported(_))));
+    }
+
+    #[test]
+    fn undo_brings_a_box_back_through_a_teleporter() {
+        let (world, resources, player) = play(TELEPORTER_LEVEL, "R", Rules::default());
+        let the_box = box_at(&world, (3, 3));
+
+        assert!(undo(&world, &resources).unwrap());
+        assert_eq!(position(&world, the_box), (2, 1));
+        assert_eq!(position(&world, player), (1, 1));
+    }
+
+    #[test]
+    fn teleporters_come_in_pairs() {
+        let map = "
+        W W W W W
+        W P T1 . W
+        W W W W W
+        ";
+        let error = verify(map, "r", Rules::default()).err().unwrap();
+        assert_eq!(error, "teleporter T1 needs exactly two tiles");
+    }
+}